@@ -16,7 +16,8 @@ fn main() {
     prost_build::compile_protos(
         &[
             "./src/proto/csimsg.proto",
-            "./src/proto/telemetrymsg.proto"
+            "./src/proto/telemetrymsg.proto",
+            "./src/proto/archivemsg.proto"
         ],
         &["./src/proto"])
         .expect("error compiling protobuf files");
@@ -0,0 +1,214 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use prost::Message;
+
+use crate::config;
+use crate::throwie::{ArchiveRecord, CsiMessage};
+use crate::writer::{self, Queue, Writer};
+
+const ARCHIVE_PREFIX: &str = "csi-";
+const ARCHIVE_SUFFIX: &str = ".pb.gz";
+const PART_SUFFIX: &str = ".part";
+// how often an idle archive checks whether the current file is due to be rotated
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// the handler side of the archive, frames are queued here and written by the archive thread
+pub struct Archiver {
+    queue: Queue<ArchiveRecord>,
+}
+
+impl Archiver {
+    pub fn record(&self, csi: &CsiMessage, addr: SocketAddr, received_us: i64) {
+        let record = ArchiveRecord {
+            received_us,
+            source_addr: addr.to_string(),
+            csi: csi.clone(),
+        };
+        self.queue.send(record);
+    }
+}
+
+struct ArchiveFile {
+    // where the file is renamed to once closed
+    path: PathBuf,
+    encoder: GzEncoder<File>,
+    // bytes written before compression
+    bytes: u64,
+    opened_at: Instant,
+}
+
+struct ArchiveWriter {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_file_age: Option<Duration>,
+    retention: Option<Duration>,
+    retention_max_bytes: u64,
+    current: Option<ArchiveFile>,
+    buffer: Vec<u8>,
+}
+
+impl Writer for ArchiveWriter {
+    type Item = ArchiveRecord;
+    const NAME: &'static str = "archive";
+    const ITEMS: &'static str = "frames";
+
+    fn write(&mut self, record: ArchiveRecord) -> io::Result<()> {
+        let file = match &mut self.current {
+            Some(f) => f,
+            None => self.current.insert(open_file(&self.dir)?),
+        };
+
+        self.buffer.clear();
+        record.encode_length_delimited(&mut self.buffer)
+            .expect("a Vec always has room to encode into");
+        file.encoder.write_all(&self.buffer)?;
+        file.bytes += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        self.rotate_if_due()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl ArchiveWriter {
+    fn rotate_if_due(&mut self) -> io::Result<()> {
+        let Some(file) = &self.current else {
+            return Ok(())
+        };
+
+        let too_big = file.bytes >= self.max_file_bytes;
+        let too_old = self.max_file_age.is_some_and(|age| file.opened_at.elapsed() >= age);
+        if too_big || too_old {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let Some(file) = self.current.take() else {
+            return Ok(())
+        };
+
+        let inner = file.encoder.finish()?;
+        inner.sync_all()?;
+        fs::rename(part_path(&file.path), &file.path)?;
+
+        self.apply_retention()
+    }
+
+    // delete closed files past retention_secs, then the oldest while over retention_max_bytes
+    fn apply_retention(&self) -> io::Result<()> {
+        let mut files = archive_files(&self.dir)?;
+        let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+
+        // nothing can be older than a retention reaching back before the epoch
+        if let Some(cutoff) = self.retention.and_then(|retention| SystemTime::now().checked_sub(retention)) {
+            for (path, size, modified) in &files {
+                if *modified < cutoff {
+                    println!("Deleting archive file {} (older than retention).", path.display());
+                    fs::remove_file(path)?;
+                    total -= size;
+                }
+            }
+            files.retain(|(_, _, modified)| *modified >= cutoff);
+        }
+
+        if self.retention_max_bytes > 0 {
+            for (path, size, _) in &files {
+                if total <= self.retention_max_bytes {
+                    break;
+                }
+                println!("Archive exceeds {} bytes, deleting oldest file {}.", self.retention_max_bytes, path.display());
+                fs::remove_file(path)?;
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_SUFFIX);
+    PathBuf::from(part)
+}
+
+// files are named by the time they were opened, so sorting the names sorts them oldest first
+fn open_file(dir: &Path) -> io::Result<ArchiveFile> {
+    let mut opened_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let mut path = dir.join(format!("{}{:016}{}", ARCHIVE_PREFIX, opened_ms, ARCHIVE_SUFFIX));
+    // tiny max_file_bytes can rotate more than once a millisecond
+    while path.exists() {
+        opened_ms += 1;
+        path = dir.join(format!("{}{:016}{}", ARCHIVE_PREFIX, opened_ms, ARCHIVE_SUFFIX));
+    }
+
+    let file = File::create(part_path(&path))?;
+    Ok(ArchiveFile {
+        path,
+        encoder: GzEncoder::new(file, Compression::default()),
+        bytes: 0,
+        opened_at: Instant::now(),
+    })
+}
+
+// closed archive files with their sizes and modification times, oldest first
+pub fn archive_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue
+        };
+        if name.starts_with(ARCHIVE_PREFIX) && name.ends_with(ARCHIVE_SUFFIX) {
+            let metadata = entry.metadata()?;
+            files.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// starts the archive thread if enabled. it finishes the current file and exits once
+// the returned Archiver is dropped.
+pub fn start() -> Option<(Archiver, JoinHandle<()>)> {
+    let config = config::get().lock().unwrap().archive.clone();
+    if !config.enabled {
+        return None
+    }
+
+    let dir = PathBuf::from(&config.dir);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("Could not create archive directory `{}`: {}", config.dir, e);
+        exit(1);
+    }
+
+    let writer = ArchiveWriter {
+        dir,
+        max_file_bytes: config.max_file_bytes,
+        max_file_age: (config.max_file_secs > 0).then(|| Duration::from_secs(config.max_file_secs)),
+        retention: (config.retention_secs > 0).then(|| Duration::from_secs(config.retention_secs)),
+        retention_max_bytes: config.retention_max_bytes,
+        current: None,
+        buffer: Vec::new(),
+    };
+
+    let (queue, handle) = writer::start(writer, config.queue_size, IDLE_CHECK_INTERVAL);
+    println!("Archiving raw CSI to `{}`.", config.dir);
+
+    Some((Archiver { queue }, handle))
+}
@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+
+use base64::Engine;
+use clap::{Parser, Subcommand, ValueEnum};
+use influxdb::Query;
+
+use crate::{config, filter, handler, layout, sensors};
+use crate::sink::influx::{self, InfluxClient};
+use crate::handler::HandlerState;
+use crate::message::MessageData;
+use crate::record::Record;
+
+pub const VERSION: &str = include_str!("../version.txt").trim_ascii();
+
+#[derive(Parser)]
+#[command(version = VERSION, about = "Collects CSI and telemetry from throwie sensors.")]
+pub struct Cli {
+    /// Path to app.toml, keys can be overridden with THROWIE_<SECTION>__<KEY> variables.
+    #[arg(long, global = true, env = config::CONFIG_PATH_ENV)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Receive messages from sensors and write readings to the enabled sinks (default).
+    Serve,
+    /// Parse and validate the config file, then check InfluxDB is reachable (if enabled).
+    CheckConfig,
+    /// Decode a single datagram and print the resulting readings as InfluxDB line protocol.
+    Decode {
+        /// Datagram contents, including the leading format byte.
+        datagram: String,
+        #[arg(long, value_enum, default_value_t = Encoding::Hex)]
+        encoding: Encoding,
+    },
+    /// Feed datagrams from a CSI archive (file or directory) or a pcap capture through the handlers.
+    Replay {
+        input: PathBuf,
+        /// Multiple of the original timing, e.g. 10 replays ten times faster.
+        #[arg(long, default_value_t = 1.0, conflicts_with = "fast")]
+        speed: f64,
+        /// Replay as fast as possible, ignoring the original timing.
+        #[arg(long)]
+        fast: bool,
+        /// Print readings as line protocol instead of writing them to the enabled sinks.
+        #[arg(long)]
+        print: bool,
+    },
+    /// Print the server version.
+    Version,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+pub async fn check_config() {
+    let path = config::path();
+    if let Err(e) = config::load(path) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    // the sensor registry, filter rules and layouts exit with an error of their own if invalid
+    sensors::registry();
+    filter::get();
+    layout::get();
+    println!("Config file `{}` is valid.", path.display());
+
+    if !config::get().lock().unwrap().influx.enabled {
+        return
+    }
+    let db = InfluxClient::new();
+    match db.ping().await {
+        Ok((build, version)) => println!("Connected to InfluxDB {} ({}).", version, build),
+        Err(e) => {
+            eprintln!("InfluxDB check failed: {}", e);
+            exit(1);
+        }
+    }
+}
+
+pub fn decode(datagram: &str, encoding: Encoding) {
+    let datagram = datagram.trim();
+    let bytes = match encoding {
+        Encoding::Hex => hex::decode(datagram).map_err(|e| e.to_string()),
+        Encoding::Base64 => base64::engine::general_purpose::STANDARD.decode(datagram).map_err(|e| e.to_string()),
+    };
+    let bytes = match bytes {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Could not decode datagram: {}", e);
+            exit(1);
+        }
+    };
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    let state = HandlerState::default();
+    let records = match MessageData::from_datagram(&bytes, addr)
+        .and_then(|message| handler::handle_message(message, &state)) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    print_records(records);
+}
+
+pub fn print_records(records: Vec<Record>) {
+    let precision = config::get().lock().unwrap().influx.precision;
+    for record in records {
+        match influx::to_query(&record, precision).build() {
+            Ok(line) => println!("{}", line.get()),
+            Err(e) => eprintln!("Could not build line protocol for reading: {}", e),
+        }
+    }
+}
@@ -1,16 +1,39 @@
-use std::fs;
+use std::collections::HashMap;
+use std::{env, fs};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Mutex, OnceLock};
 use serde_derive::Deserialize;
 
-use toml;
+use crate::error::ConfigError;
 
-const CONFIG_PATH: &str = "src/config/app.toml";
+pub const CONFIG_PATH_ENV: &str = "THROWIE_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "src/config/app.toml";
+const ENV_PREFIX: &str = "THROWIE_";
+const ENV_SEPARATOR: &str = "__";
+
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
 #[derive(Clone, Debug, Deserialize)]
 #[allow(unused)]
 pub struct Buffer {
+    // most readings kept per link
     pub window_size: usize,
+    // readings older than this, relative to the newest, fall out of the window
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+    // how often window metrics are written per link, 0 to disable
+    #[serde(default = "default_hop_ms")]
+    pub hop_ms: u64,
+}
+
+fn default_window_ms() -> u64 {
+    1000
+}
+
+fn default_hop_ms() -> u64 {
+    500
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -19,19 +42,672 @@ pub struct Message {
     pub address: String,
     pub csi_frame_size: i16,
     pub port: u16,
+    #[serde(default = "default_max_decompressed_size")]
+    pub max_decompressed_size: usize,
+    // identify sensors by the last three mac octets without leading zeroes, as older releases did
+    #[serde(default)]
+    pub legacy_mac_format: bool,
+}
+
+fn default_max_decompressed_size() -> usize {
+    16384
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[allow(unused)]
 pub struct Influx {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
     pub protocol: String,
     pub address: String,
-    pub port: i16,
+    pub port: u16,
+    // v1 writes to /write, v2 and v3 write to /api/v2/write
+    #[serde(default)]
+    pub api: InfluxApi,
+    // PEM encoded CA certificate to trust when protocol is https
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    // v1 auth
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // v2/v3 auth, also sent to v1 if set
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub org: Option<String>,
+    // defaults to database
+    #[serde(default)]
+    pub bucket: Option<String>,
+    #[serde(default)]
+    pub precision: Precision,
     pub write_batch_size: i32,
+    // max time a reading waits in the batch before it's written, 0 to only flush on write_batch_size
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    // retries per batch while influx is unreachable, backing off exponentially between them
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    // batches that still can't be written are kept here until influx is back, unset to disable
+    #[serde(default)]
+    pub spool_dir: Option<String>,
+    // oldest batches are discarded once the spool grows past this
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64,
 
     pub database: String,
     pub csi_metrics_measurement: String,
     pub sensor_telemetry_measurement: String,
+    #[serde(default = "default_csi_window_measurement")]
+    pub csi_window_measurement: String,
+    #[serde(default = "default_vitals_measurement")]
+    pub vitals_measurement: String,
+    #[serde(default = "default_presence_measurement")]
+    pub presence_measurement: String,
+    #[serde(default = "default_rejected_measurement")]
+    pub rejected_measurement: String,
+}
+
+fn default_csi_window_measurement() -> String {
+    String::from("csi_window")
+}
+
+fn default_vitals_measurement() -> String {
+    String::from("vitals")
+}
+
+fn default_presence_measurement() -> String {
+    String::from("presence")
+}
+
+fn default_rejected_measurement() -> String {
+    String::from("rejected")
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxApi {
+    #[default]
+    V1,
+    V2,
+    V3,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Ns,
+    #[default]
+    Us,
+    Ms,
+    S,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    250
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    5000
+}
+
+fn default_spool_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+// appends every reading to a local file as one JSON object per line
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct File {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_file_path")]
+    pub path: String,
+    #[serde(default = "default_file_write_batch_size")]
+    pub write_batch_size: usize,
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+impl Default for File {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_file_path(),
+            write_batch_size: default_file_write_batch_size(),
+            flush_interval_ms: default_flush_interval_ms(),
+        }
+    }
+}
+
+fn default_file_path() -> String {
+    String::from("readings.jsonl")
+}
+
+fn default_file_write_batch_size() -> usize {
+    50
+}
+
+// keeps every raw csi frame on disk, see src/proto/archivemsg.proto for the format
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Archive {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_archive_dir")]
+    pub dir: String,
+    // a new file is started once the current one reaches either limit (bytes before compression)
+    #[serde(default = "default_archive_max_file_bytes")]
+    pub max_file_bytes: u64,
+    #[serde(default = "default_archive_max_file_secs")]
+    pub max_file_secs: u64,
+    // closed files are deleted once older than retention_secs, or oldest first while the
+    // archive is larger than retention_max_bytes. 0 disables either
+    #[serde(default)]
+    pub retention_secs: u64,
+    #[serde(default)]
+    pub retention_max_bytes: u64,
+    // frames waiting to be written, frames are dropped rather than slowing the handlers once full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: default_archive_dir(),
+            max_file_bytes: default_archive_max_file_bytes(),
+            max_file_secs: default_archive_max_file_secs(),
+            retention_secs: 0,
+            retention_max_bytes: 0,
+            queue_size: default_queue_size(),
+        }
+    }
+}
+
+fn default_archive_dir() -> String {
+    String::from("archive")
+}
+
+fn default_archive_max_file_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_archive_max_file_secs() -> u64 {
+    3600
+}
+
+fn default_queue_size() -> usize {
+    4096
+}
+
+// writes each link's csi matrix, complex csi, amplitude and phase rows, timestamps, rssi and
+// sequence numbers to .npy files
+// under dir/session-<unix ms>/<mac>-<antenna>/
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Export {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_export_dir")]
+    pub dir: String,
+    // rows waiting to be written, rows are dropped rather than slowing the handlers once full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Default for Export {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: default_export_dir(),
+            queue_size: default_queue_size(),
+        }
+    }
+}
+
+fn default_export_dir() -> String {
+    String::from("export")
+}
+
+// how grid points between two readings are filled when resampling
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GapPolicy {
+    // repeat the earlier reading
+    Hold,
+    // interpolate between the readings either side
+    #[default]
+    Linear,
+    // use the nearest reading within half a period, otherwise mark the point missing
+    Missing,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Resample {
+    // resample each link's readings onto a uniform grid before window metrics
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_rate_hz")]
+    pub rate_hz: f64,
+    #[serde(default)]
+    pub gap_policy: GapPolicy,
+    // gaps longer than this aren't filled and start a new window, 0 for as long as the longest window
+    #[serde(default = "default_max_gap_ms")]
+    pub max_gap_ms: u64,
+}
+
+impl Default for Resample {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_hz: default_rate_hz(),
+            gap_policy: GapPolicy::default(),
+            max_gap_ms: default_max_gap_ms(),
+        }
+    }
+}
+
+fn default_rate_hz() -> f64 {
+    50.0
+}
+
+fn default_max_gap_ms() -> u64 {
+    500
+}
+
+// one step of the denoise chain, windows are in readings
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterStage {
+    // replace readings more than threshold scaled MADs from the median of the last window
+    Hampel { window: usize, threshold: f32 },
+    // low-pass
+    Butterworth { order: usize, cutoff_hz: f64 },
+    // polynomial of the given order fitted to the last window, evaluated at the newest reading
+    SavitzkyGolay { window: usize, order: usize },
+    // subtract a moving average over roughly the last window
+    DcRemoval { window: usize },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Denoise {
+    // nominal reading rate of each link, used to design the butterworth filter
+    #[serde(default = "default_rate_hz")]
+    pub rate_hz: f64,
+    // applied in order to each subcarrier of csi_matrix, e.g. [[denoise.chain]]
+    #[serde(default)]
+    pub chain: Vec<FilterStage>,
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self {
+            rate_hz: default_rate_hz(),
+            chain: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Vitals {
+    // estimate breathing and heart rate per link, needs resampling enabled
+    #[serde(default)]
+    pub enabled: bool,
+    // resampled csi each estimate is made from
+    #[serde(default = "default_vitals_window_secs")]
+    pub window_secs: u64,
+    // how often estimates are written per link
+    #[serde(default = "default_vitals_hop_ms")]
+    pub hop_ms: u64,
+    // pass bands in Hz, as [low, high]
+    #[serde(default = "default_breathing_hz")]
+    pub breathing_hz: [f64; 2],
+    #[serde(default = "default_heart_rate_hz")]
+    pub heart_rate_hz: [f64; 2],
+    // of the butterworth band-pass filters
+    #[serde(default = "default_vitals_filter_order")]
+    pub filter_order: usize,
+}
+
+impl Default for Vitals {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: default_vitals_window_secs(),
+            hop_ms: default_vitals_hop_ms(),
+            breathing_hz: default_breathing_hz(),
+            heart_rate_hz: default_heart_rate_hz(),
+            filter_order: default_vitals_filter_order(),
+        }
+    }
+}
+
+fn default_vitals_window_secs() -> u64 {
+    30
+}
+
+fn default_vitals_hop_ms() -> u64 {
+    5000
+}
+
+fn default_breathing_hz() -> [f64; 2] {
+    [0.1, 0.5]
+}
+
+fn default_heart_rate_hz() -> [f64; 2] {
+    [0.8, 2.0]
+}
+
+fn default_vitals_filter_order() -> usize {
+    2
+}
+
+// levels of a window's metrics, states are entered at or past enter and left once back past exit
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[allow(unused)]
+pub struct PresenceThresholds {
+    // on mean_subcarrier_variance
+    #[serde(default = "default_occupied_enter")]
+    pub occupied_enter: f32,
+    #[serde(default = "default_occupied_exit")]
+    pub occupied_exit: f32,
+    #[serde(default = "default_motion_enter")]
+    pub motion_enter: f32,
+    #[serde(default = "default_motion_exit")]
+    pub motion_exit: f32,
+    // on mean_pcc, which falls as things move
+    #[serde(default = "default_motion_pcc_enter")]
+    pub motion_pcc_enter: f32,
+    #[serde(default = "default_motion_pcc_exit")]
+    pub motion_pcc_exit: f32,
+}
+
+impl Default for PresenceThresholds {
+    fn default() -> Self {
+        Self {
+            occupied_enter: default_occupied_enter(),
+            occupied_exit: default_occupied_exit(),
+            motion_enter: default_motion_enter(),
+            motion_exit: default_motion_exit(),
+            motion_pcc_enter: default_motion_pcc_enter(),
+            motion_pcc_exit: default_motion_pcc_exit(),
+        }
+    }
+}
+
+fn default_occupied_enter() -> f32 {
+    0.05
+}
+
+fn default_occupied_exit() -> f32 {
+    0.03
+}
+
+fn default_motion_enter() -> f32 {
+    0.5
+}
+
+fn default_motion_exit() -> f32 {
+    0.3
+}
+
+fn default_motion_pcc_enter() -> f32 {
+    0.9
+}
+
+fn default_motion_pcc_exit() -> f32 {
+    0.95
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct PresenceOverrides {
+    pub occupied_enter: Option<f32>,
+    pub occupied_exit: Option<f32>,
+    pub motion_enter: Option<f32>,
+    pub motion_exit: Option<f32>,
+    pub motion_pcc_enter: Option<f32>,
+    pub motion_pcc_exit: Option<f32>,
+}
+
+impl PresenceOverrides {
+    pub fn apply(&self, thresholds: PresenceThresholds) -> PresenceThresholds {
+        PresenceThresholds {
+            occupied_enter: self.occupied_enter.unwrap_or(thresholds.occupied_enter),
+            occupied_exit: self.occupied_exit.unwrap_or(thresholds.occupied_exit),
+            motion_enter: self.motion_enter.unwrap_or(thresholds.motion_enter),
+            motion_exit: self.motion_exit.unwrap_or(thresholds.motion_exit),
+            motion_pcc_enter: self.motion_pcc_enter.unwrap_or(thresholds.motion_pcc_enter),
+            motion_pcc_exit: self.motion_pcc_exit.unwrap_or(thresholds.motion_pcc_exit),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Presence {
+    // track empty/occupied/motion per link and zone from the window metrics
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub thresholds: PresenceThresholds,
+    // how long a new state has to last before it's reported, and before a link is empty
+    #[serde(default = "default_presence_hold_ms")]
+    pub hold_ms: u64,
+    #[serde(default = "default_empty_hold_ms")]
+    pub empty_hold_ms: u64,
+    // a link which hasn't had a window for this long no longer counts towards its zone. 0 to keep it
+    #[serde(default = "default_link_timeout_ms")]
+    pub link_timeout_ms: u64,
+    // learn each link's thresholds over its first calibrate_secs, which must be empty. 0 to disable
+    #[serde(default)]
+    pub calibrate_secs: u64,
+    // calibrated enter thresholds are this many standard deviations past the baseline,
+    // exits half as many
+    #[serde(default = "default_occupied_sigmas")]
+    pub occupied_sigmas: f32,
+    #[serde(default = "default_motion_sigmas")]
+    pub motion_sigmas: f32,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            thresholds: PresenceThresholds::default(),
+            hold_ms: default_presence_hold_ms(),
+            empty_hold_ms: default_empty_hold_ms(),
+            link_timeout_ms: default_link_timeout_ms(),
+            calibrate_secs: 0,
+            occupied_sigmas: default_occupied_sigmas(),
+            motion_sigmas: default_motion_sigmas(),
+        }
+    }
+}
+
+fn default_presence_hold_ms() -> u64 {
+    2000
+}
+
+fn default_empty_hold_ms() -> u64 {
+    30000
+}
+
+fn default_link_timeout_ms() -> u64 {
+    60000
+}
+
+fn default_occupied_sigmas() -> f32 {
+    3.0
+}
+
+fn default_motion_sigmas() -> f32 {
+    10.0
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CrcPolicy {
+    // discard frames whose csi_data doesn't match csi_crc32
+    #[default]
+    Drop,
+    // keep mismatched frames, but write them with crc_valid = false
+    Flag,
+    // don't verify csi_crc32 at all
+    Accept,
+}
+
+// order of the two bytes of each subcarrier in csi_data
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IqOrder {
+    // as documented by esp-idf
+    #[default]
+    ImagReal,
+    RealImag,
+}
+
+// the chip a sensor runs on. the original esp32 reports the first four bytes of
+// csi_data as invalid, later chips don't.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Chip {
+    #[default]
+    Esp32,
+    Esp32s2,
+    Esp32s3,
+    Esp32c3,
+    Esp32c6,
+}
+
+// how csi amplitudes are normalised before use, see csi::get_scaling_factor
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scaling {
+    // raw amplitudes, for sensors with the agc disabled or gain fixed
+    None,
+    // mean subcarrier power equals the rssi
+    #[default]
+    Rssi,
+    // mean subcarrier power equals the rssi less the noise floor, or the rssi for frames
+    // which aren't above the noise floor
+    Snr,
+}
+
+// a subcarrier layout in addition to the built-in ones. csi_data is a run of training
+// fields, each holding subcarriers 0..n/2-1 then -n/2..-1. one field is extracted.
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Layout {
+    // csi_data length in bytes
+    pub data_length: usize,
+    // subcarriers in the fields before the extracted one
+    pub field_offset: usize,
+    // subcarriers in the extracted field
+    pub field_size: usize,
+    // frequency indices to extract, e.g. [-26, ..., -1, 1, ..., 26]
+    pub subcarriers: Vec<i32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct Csi {
+    #[serde(default)]
+    pub crc_policy: CrcPolicy,
+    #[serde(default)]
+    pub iq_order: IqOrder,
+    #[serde(default)]
+    pub scaling: Scaling,
+    // use this layout for every sensor instead of choosing one by csi_data length
+    #[serde(default)]
+    pub layout: Option<String>,
+    // keyed by name, e.g. [csi.layouts.my_layout]
+    #[serde(default)]
+    pub layouts: HashMap<String, Layout>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct SensorAlias {
+    pub name: String,
+    pub room: Option<String>,
+    pub zone: Option<String>,
+    pub role: Option<String>,
+    // override csi.layout and csi.iq_order for this sensor
+    pub layout: Option<String>,
+    pub iq_order: Option<IqOrder>,
+    #[serde(default)]
+    pub chip: Chip,
+    // override any of presence's thresholds for this sensor
+    #[serde(default)]
+    pub presence: PresenceOverrides,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct Sensors {
+    // drop readings from sensors which aren't listed under sensors.devices
+    #[serde(default)]
+    pub reject_unregistered: bool,
+    // keyed by mac address, e.g. [sensors.devices."24:0A:C4:00:00:01"]
+    #[serde(default)]
+    pub devices: HashMap<String, SensorAlias>,
+}
+
+// addresses are single IPs or CIDR blocks, macs use any format MacAddress accepts.
+// deny rules win, an empty allow list allows everything.
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct Filter {
+    #[serde(default)]
+    pub allow_addresses: Vec<String>,
+    #[serde(default)]
+    pub deny_addresses: Vec<String>,
+    #[serde(default)]
+    pub allow_macs: Vec<String>,
+    #[serde(default)]
+    pub deny_macs: Vec<String>,
+    // how often the rejected counts are written, 0 to not write them
+    #[serde(default = "default_report_interval_ms")]
+    pub report_interval_ms: u64,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            allow_addresses: Vec::new(),
+            deny_addresses: Vec::new(),
+            allow_macs: Vec::new(),
+            deny_macs: Vec::new(),
+            report_interval_ms: default_report_interval_ms(),
+        }
+    }
+}
+
+fn default_report_interval_ms() -> u64 {
+    60000
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -40,21 +716,242 @@ pub struct AppConfig {
     pub buffer: Buffer,
     pub message: Message,
     pub influx: Influx,
+    #[serde(default)]
+    pub file: File,
+    #[serde(default)]
+    pub archive: Archive,
+    #[serde(default)]
+    pub export: Export,
+    #[serde(default)]
+    pub csi: Csi,
+    #[serde(default)]
+    pub resample: Resample,
+    #[serde(default)]
+    pub denoise: Denoise,
+    #[serde(default)]
+    pub vitals: Vitals,
+    #[serde(default)]
+    pub presence: Presence,
+    #[serde(default)]
+    pub sensors: Sensors,
+    #[serde(default)]
+    pub filter: Filter,
 }
 
-pub fn build() -> AppConfig{
-    let contents = match fs::read_to_string(CONFIG_PATH) {
-        Ok(c) => c,
-        Err(_) => {
-            eprintln!("Could not read config file: `{}`", CONFIG_PATH);
-            exit(1);
+impl AppConfig {
+    // catch values which parse fine but would only fail once the server is running
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, reason: &str| Err(ConfigError::ValidationError(key.to_string(), reason.to_string()));
+
+        if format!("{}:{}", self.message.address, self.message.port).parse::<SocketAddr>().is_err() {
+            return invalid("message.address", "not a valid IP address");
+        }
+        if self.message.csi_frame_size <= 0 {
+            return invalid("message.csi_frame_size", "must be greater than 0");
+        }
+        if self.buffer.window_size == 0 {
+            return invalid("buffer.window_size", "must be greater than 0");
+        }
+        if self.buffer.window_ms == 0 {
+            return invalid("buffer.window_ms", "must be greater than 0");
+        }
+        if !(self.resample.rate_hz > 0.0 && self.resample.rate_hz <= 1_000_000.0) {
+            return invalid("resample.rate_hz", "must be greater than 0 and at most 1000000");
+        }
+        if !(self.denoise.rate_hz > 0.0 && self.denoise.rate_hz.is_finite()) {
+            return invalid("denoise.rate_hz", "must be greater than 0");
+        }
+        if self.vitals.enabled {
+            if !self.resample.enabled {
+                return invalid("vitals.enabled", "needs resample.enabled, the spectra need a uniform rate");
+            }
+            if self.vitals.hop_ms == 0 {
+                return invalid("vitals.hop_ms", "must be greater than 0");
+            }
+            // filtering both ways pads each end of the window
+            if (self.vitals.window_secs as f64 * self.resample.rate_hz) < 64.0 {
+                return invalid("vitals.window_secs", "must hold at least 64 resampled readings");
+            }
+            if !(1..=4).contains(&self.vitals.filter_order) {
+                return invalid("vitals.filter_order", "must be between 1 and 4");
+            }
+            for (key, [low, high]) in [("vitals.breathing_hz", self.vitals.breathing_hz), ("vitals.heart_rate_hz", self.vitals.heart_rate_hz)] {
+                if !(low > 0.0 && low < high && high < self.resample.rate_hz / 2.0) {
+                    return invalid(key, "must be [low, high] with 0 < low < high < half of resample.rate_hz");
+                }
+            }
+        }
+        if self.presence.enabled {
+            if self.buffer.hop_ms == 0 {
+                return invalid("presence.enabled", "needs buffer.hop_ms, presence follows the window metrics");
+            }
+            if self.presence.link_timeout_ms != 0 && self.presence.link_timeout_ms <= self.buffer.hop_ms {
+                return invalid("presence.link_timeout_ms", "must be 0 or greater than buffer.hop_ms");
+            }
+            if !(self.presence.occupied_sigmas > 0.0 && self.presence.occupied_sigmas < self.presence.motion_sigmas) {
+                return invalid("presence.occupied_sigmas", "must be greater than 0 and less than presence.motion_sigmas");
+            }
+            let mut thresholds = vec![(String::from("presence"), self.presence.thresholds)];
+            for (key, alias) in &self.sensors.devices {
+                thresholds.push((format!("sensors.devices.\"{}\".presence", key), alias.presence.apply(self.presence.thresholds)));
+            }
+            for (key, t) in thresholds {
+                if t.occupied_exit > t.occupied_enter || t.motion_exit > t.motion_enter || t.motion_pcc_exit < t.motion_pcc_enter {
+                    return invalid(&key, "each exit threshold must be on the near side of its enter threshold");
+                }
+                if t.occupied_enter > t.motion_enter {
+                    return invalid(&key, "occupied_enter must not be above motion_enter");
+                }
+            }
+        }
+        for (i, stage) in self.denoise.chain.iter().enumerate() {
+            let key = |name: &str| format!("denoise.chain[{}].{}", i, name);
+            match *stage {
+                FilterStage::Hampel { window, threshold } => {
+                    if window < 3 {
+                        return invalid(&key("window"), "must be at least 3");
+                    }
+                    if threshold <= 0.0 {
+                        return invalid(&key("threshold"), "must be greater than 0");
+                    }
+                }
+                FilterStage::Butterworth { order, cutoff_hz } => {
+                    if !(1..=10).contains(&order) {
+                        return invalid(&key("order"), "must be between 1 and 10");
+                    }
+                    if !(cutoff_hz > 0.0 && cutoff_hz < self.denoise.rate_hz / 2.0) {
+                        return invalid(&key("cutoff_hz"), "must be between 0 and half of denoise.rate_hz");
+                    }
+                }
+                FilterStage::SavitzkyGolay { window, order } => {
+                    if order >= window {
+                        return invalid(&key("order"), "must be less than window");
+                    }
+                }
+                FilterStage::DcRemoval { window } => {
+                    if window == 0 {
+                        return invalid(&key("window"), "must be greater than 0");
+                    }
+                }
+            }
+        }
+        if self.influx.write_batch_size <= 0 {
+            return invalid("influx.write_batch_size", "must be greater than 0");
+        }
+        if self.file.write_batch_size == 0 {
+            return invalid("file.write_batch_size", "must be greater than 0");
+        }
+        if self.archive.max_file_bytes == 0 {
+            return invalid("archive.max_file_bytes", "must be greater than 0");
+        }
+        if self.archive.queue_size == 0 {
+            return invalid("archive.queue_size", "must be greater than 0");
+        }
+        if self.export.queue_size == 0 {
+            return invalid("export.queue_size", "must be greater than 0");
+        }
+        if !self.influx.enabled && !self.file.enabled {
+            return invalid("influx.enabled", "at least one of influx or file must be enabled");
+        }
+        if self.influx.api == InfluxApi::V2 && self.influx.org.is_none() {
+            return invalid("influx.org", "required when influx.api is v2");
+        }
+        if self.influx.api != InfluxApi::V1 && self.influx.token.is_none() && self.influx.username.is_some() {
+            return invalid("influx.token", "v2 and v3 authenticate with a token rather than a username");
+        }
+        Ok(())
+    }
+}
+
+// layer THROWIE_<SECTION>__<KEY> environment variables over the parsed file,
+// e.g. THROWIE_INFLUX__ADDRESS=10.0.0.2 replaces influx.address
+fn apply_env_overrides(table: &mut toml::Table) -> Result<Vec<(String, String, String)>, ConfigError> {
+    let mut applied = Vec::new();
+
+    for (var, raw) in env::vars() {
+        let Some(key) = var.strip_prefix(ENV_PREFIX).filter(|k| k.contains(ENV_SEPARATOR)) else {
+            continue
+        };
+        let path: Vec<String> = key.split(ENV_SEPARATOR).map(|k| k.to_lowercase()).collect();
+        let dotted_key = path.join(".");
+
+        let (last, sections) = path.split_last().unwrap();
+        let mut section = &mut *table;
+        for name in sections {
+            section = section.entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| ConfigError::OverrideError(var.clone(), dotted_key.clone()))?;
+        }
+
+        // keep string keys as strings, otherwise read the value as a toml literal (numbers, bools, arrays).
+        // keys missing from the file are retried as strings in load if the literal doesn't fit
+        let value = match section.get(last) {
+            Some(toml::Value::String(_)) => toml::Value::String(raw.clone()),
+            _ => toml::from_str::<toml::Table>(&format!("v = {}", raw))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .unwrap_or(toml::Value::String(raw.clone())),
+        };
+        section.insert(last.clone(), value);
+        applied.push((dotted_key, var, raw));
+    }
+
+    Ok(applied)
+}
+
+pub fn load(path: &Path) -> Result<AppConfig, ConfigError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ConfigError::ReadError(path.display().to_string(), e))?;
+
+    let mut table: toml::Table = toml::from_str(&contents)
+        .map_err(|e| ConfigError::ParseError(path.display().to_string(), e))?;
+    let overrides = apply_env_overrides(&mut table)?;
+
+    let config: AppConfig = loop {
+        let e = match serde_path_to_error::deserialize(toml::Value::Table(table.clone())) {
+            Ok(config) => break config,
+            Err(e) => e,
+        };
+        let key = e.path().to_string();
+        let Some((_, var, raw)) = overrides.iter().find(|(k, _, _)| *k == key) else {
+            let source = format!("config file `{}`", path.display());
+            return Err(ConfigError::InvalidKeyError(key, source, e.into_inner().message().to_string()))
+        };
+
+        // e.g. THROWIE_INFLUX__PASSWORD=12345678 when the file leaves password commented out
+        let value = key.rsplit_once('.').and_then(|(sections, last)| {
+            sections.split('.')
+                .try_fold(&mut table, |t, name| t.get_mut(name)?.as_table_mut())?
+                .get_mut(last)
+        });
+        match value {
+            Some(value) if !value.is_str() => *value = toml::Value::String(raw.clone()),
+            _ => {
+                let source = format!("environment variable {}", var);
+                return Err(ConfigError::InvalidKeyError(key, source, e.into_inner().message().to_string()))
+            }
         }
     };
 
-    match toml::from_str(&contents) {
-        Ok(d) => d,
-        Err(_) => {
-            eprintln!("Unable to parse config file: `{}`", CONFIG_PATH);
+    config.validate()?;
+    Ok(config)
+}
+
+// set from --config or THROWIE_CONFIG, must happen before the config is first read
+pub fn set_path(path: PathBuf) {
+    CONFIG_PATH.set(path).expect("config path was set after the config was read");
+}
+
+pub fn path() -> &'static Path {
+    CONFIG_PATH.get_or_init(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+pub fn build() -> AppConfig{
+    match load(path()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
             exit(1);
         }
     }
@@ -63,4 +960,4 @@ pub fn build() -> AppConfig{
 pub fn get() -> &'static Mutex<AppConfig> {
     static CONFIG: OnceLock<Mutex<AppConfig>> = OnceLock::new();
     CONFIG.get_or_init(|| Mutex::new(build()))
-}
\ No newline at end of file
+}
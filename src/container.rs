@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use inflate::InflateStream;
+
+use crate::error::RecvMessageError;
+
+// compressed containers are laid out as:
+//   [u16 LE compressed size][zlib stream of `compressed size` bytes]
+// and decompress into fixed size slots of (csi_frame_size + 1) bytes:
+//   [u8 protobuf size][protobuf][padding up to csi_frame_size]
+const SIZE_PREFIX_LEN: usize = 2;
+
+pub struct CompressedContainer {
+    data: Vec<u8>,
+    frames: Vec<Range<usize>>,
+    pub frame_count: usize,
+    pub discarded: usize,
+}
+
+impl CompressedContainer {
+    pub fn parse(payload: &[u8], csi_frame_size: usize, max_decompressed_size: usize) -> Result<Self, RecvMessageError> {
+        let Some(size_bytes) = payload.get(0 .. SIZE_PREFIX_LEN) else {
+            return Err(RecvMessageError::ContainerTruncatedError(SIZE_PREFIX_LEN, payload.len()))
+        };
+        let expected_compressed_size = u16::from_le_bytes([size_bytes[0], size_bytes[1]]) as usize;
+        let expected_end_index = expected_compressed_size + SIZE_PREFIX_LEN;
+
+        let Some(compressed_payload) = payload.get(SIZE_PREFIX_LEN .. expected_end_index) else {
+            return Err(RecvMessageError::ContainerTruncatedError(expected_end_index, payload.len()))
+        };
+
+        let data = decompress(compressed_payload, max_decompressed_size)?;
+
+        let slot_size = csi_frame_size + 1;
+        if data.is_empty() || !data.len().is_multiple_of(slot_size) {
+            return Err(RecvMessageError::ContainerFrameCountError(data.len(), slot_size))
+        }
+
+        let frame_count = data.len() / slot_size;
+        let mut frames = Vec::with_capacity(frame_count);
+        let mut discarded = 0;
+
+        for i in 0 .. frame_count {
+            let protobuf_size = data[slot_size * i] as usize;
+
+            // the length prefix can never point outside of its own slot
+            if protobuf_size == 0 || protobuf_size > csi_frame_size {
+                println!("{}", RecvMessageError::ContainerFrameLengthError(i, protobuf_size, csi_frame_size));
+                discarded += 1;
+                continue
+            }
+
+            let protobuf_start = (slot_size * i) + 1;
+            frames.push(protobuf_start .. protobuf_start + protobuf_size);
+        }
+
+        Ok(Self {
+            data,
+            frames,
+            frame_count,
+            discarded
+        })
+    }
+
+    // protobuf contents of every frame whose length prefix was valid
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> {
+        self.frames.iter().map(|r| &self.data[r.clone()])
+    }
+}
+
+// inflate a zlib stream, bailing out as soon as the output grows past the given limit
+fn decompress(compressed: &[u8], max_decompressed_size: usize) -> Result<Vec<u8>, RecvMessageError> {
+    let mut stream = InflateStream::from_zlib();
+    let mut decompressed = Vec::new();
+
+    let mut n = 0;
+    loop {
+        let (num_bytes_read, bytes) = stream.update(&compressed[n..])
+            .map_err(RecvMessageError::MessageDecompressionError)?;
+        if bytes.is_empty() {
+            break;
+        }
+        if decompressed.len() + bytes.len() > max_decompressed_size {
+            return Err(RecvMessageError::ContainerSizeExceededError(max_decompressed_size))
+        }
+        n += num_bytes_read;
+        decompressed.extend_from_slice(bytes);
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    const FRAME_SIZE: usize = 4;
+    const MAX_SIZE: usize = 1024;
+
+    // a datagram payload holding data as its zlib stream
+    fn payload(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut payload = (compressed.len() as u16).to_le_bytes().to_vec();
+        payload.extend(compressed);
+        payload
+    }
+
+    fn error(payload: &[u8]) -> RecvMessageError {
+        match CompressedContainer::parse(payload, FRAME_SIZE, MAX_SIZE) {
+            Ok(_) => panic!("container parsed"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn needs_the_size_prefix() {
+        assert!(matches!(error(&[]), RecvMessageError::ContainerTruncatedError(2, 0)));
+        assert!(matches!(error(&[7]), RecvMessageError::ContainerTruncatedError(2, 1)));
+    }
+
+    #[test]
+    fn compressed_size_must_fit_the_datagram() {
+        let mut truncated = payload(&[1, 0xAA, 0, 0, 0]);
+        let len = truncated.len();
+        truncated.pop();
+        assert!(matches!(error(&truncated), RecvMessageError::ContainerTruncatedError(expected, got)
+            if expected == len && got == len - 1));
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let result = CompressedContainer::parse(&payload(&[0; 100]), FRAME_SIZE, 50);
+        assert!(matches!(result, Err(RecvMessageError::ContainerSizeExceededError(50))));
+    }
+
+    #[test]
+    fn output_must_be_whole_slots() {
+        assert!(matches!(error(&payload(&[1, 0xAA, 0, 0, 0, 1, 0xBB])), RecvMessageError::ContainerFrameCountError(7, 5)));
+        assert!(matches!(error(&payload(&[])), RecvMessageError::ContainerFrameCountError(0, 5)));
+    }
+
+    #[test]
+    fn invalid_length_prefixes_are_discarded() {
+        let data = [
+            3, 1, 2, 3, 0,
+            0, 9, 9, 9, 9,
+            5, 9, 9, 9, 9,
+            4, 4, 5, 6, 7,
+        ];
+        let container = CompressedContainer::parse(&payload(&data), FRAME_SIZE, MAX_SIZE).unwrap();
+        assert_eq!(container.frame_count, 4);
+        assert_eq!(container.discarded, 2);
+        assert_eq!(container.frames().collect::<Vec<_>>(), [&[1, 2, 3][..], &[4, 5, 6, 7][..]]);
+    }
+
+    #[test]
+    fn parses_a_valid_container() {
+        let mut data = Vec::new();
+        for i in 0..3 {
+            data.extend([2, i, i + 10, 0, 0]);
+        }
+        // anything after the compressed stream is ignored
+        let mut payload = payload(&data);
+        payload.push(0xFF);
+
+        let container = CompressedContainer::parse(&payload, FRAME_SIZE, MAX_SIZE).unwrap();
+        assert_eq!((container.frame_count, container.discarded), (3, 0));
+        assert_eq!(container.frames().collect::<Vec<_>>(), [&[0, 10][..], &[1, 11], &[2, 12]]);
+    }
+
+    #[test]
+    fn corrupt_streams_are_errors() {
+        assert!(matches!(error(&[3, 0, 1, 2, 3]), RecvMessageError::MessageDecompressionError(_)));
+    }
+}
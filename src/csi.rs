@@ -1,134 +1,369 @@
-use std::collections::VecDeque;
 use ndarray_stats::CorrelationExt;
 
-use influxdb::Timestamp;
-use influxdb::InfluxDbWriteable;
-
 use crate::throwie::CsiMessage;
 
+use std::f32::consts::PI;
+
 use ndarray::{Array, Ix2, Axis, concatenate};
-use ndarray_stats;
+use num::complex::Complex32;
 use prost::{DecodeError, Message};
 use ringbuffer::AllocRingBuffer;
-use crate::error::RecvMessageError;
-
-const FILTER_SUBCARRIERS: [u8; 11] = [0, 1, 28, 29, 30, 31, 32, 33, 34, 35, 36];
-const REQUIRED_SUBCARRIERS: [usize; 53] = [2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63];
-// const REQUIRED_SUBCARRIERS: [usize; 60] = [4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63];
-
-pub const ACTIVE_SUBCARRIERS: usize = 53;
+use crate::config::{self, Scaling};
+use crate::denoise::FilterChain;
+use crate::error::{CSIReadingError, RecvMessageError};
+use crate::layout;
+use crate::mac::MacAddress;
+use crate::presence::PresenceDetector;
+use crate::record::Record;
+use crate::resample::{Resampler, Sample};
+use crate::sensors::SensorInfo;
+use crate::vitals::VitalsTracker;
 
-#[derive(Clone, InfluxDbWriteable, Debug)]
+#[derive(Clone, Debug)]
 pub struct CSIReading {
-    pub time: Timestamp,
-    rssi: i8,
+    pub rssi: i8,
     noise_floor: i32,
-    pub correlation_coefficient: f32,
+    // against the link's previous reading, unset when there's nothing to compare with
+    pub correlation_coefficient: Option<f32>,
     pub sequence_identifier: i32,
     pub interval: i32,
-    #[influxdb(tag)] pub mac: String,
-    #[influxdb(tag)] pub antenna: i8,
+    pub crc_valid: bool,
+    pub crc_mismatches: i64,
+    pub mac: String,
+    pub antenna: i8,
+    pub sensor_name: String,
+    pub room: String,
+    pub zone: String,
+    pub role: String,
 
-    #[influxdb(ignore)] pub csi_matrix: Array<f32, Ix2>,
-    #[influxdb(ignore)] pub timestamp_us: u128
+    pub mac_address: MacAddress,
+    // per subcarrier power in dBm (dB when csi.scaling is none) after the denoise chain,
+    // used for the correlation coefficient
+    pub csi_matrix: Array<f32, Ix2>,
+    // channel response per subcarrier as reported by the sensor
+    pub csi_complex: Array<Complex32, Ix2>,
+    // phase of csi_complex with the sto/cfo slope and offset removed, see sanitise_phase
+    pub phase: Array<f32, Ix2>,
+    pub timestamp_us: u128
 }
 
 pub struct CSIStore {
     pub reading: CSIReading,
-    pub buffer: AllocRingBuffer<CSIReading>,
-    pub counter: usize
+    // denoise chain state, applied to csi_matrix before anything else uses it
+    pub filters: FilterChain,
+    // samples within buffer.window_ms of the newest, oldest first
+    pub buffer: AllocRingBuffer<Sample>,
+    // set when resampling is enabled
+    pub resampler: Option<Resampler>,
+    // set when vitals are enabled, fed from the resampler
+    pub vitals: Option<VitalsTracker>,
+    // set when presence is enabled, fed from the window metrics
+    pub presence: Option<PresenceDetector>,
+    // timestamp of the sample the last window metrics were written with
+    pub last_window_us: u128
 }
 
-impl CSIReading {
-    pub fn new(msg: &CsiMessage) -> Self{
-        let timestamp_us = u128::try_from(msg.timestamp).unwrap();
-        let time = Timestamp::Microseconds(timestamp_us).into();
+impl TryFrom<&CsiMessage> for CSIReading {
+    type Error = CSIReadingError;
+
+    fn try_from(msg: &CsiMessage) -> Result<Self, Self::Error> {
+        let timestamp_us = u128::try_from(msg.timestamp)
+            .map_err(|_| CSIReadingError::NegativeTimestamp(msg.timestamp))?;
+
+        let antenna = i8::try_from(msg.antenna)
+            .map_err(|_| CSIReadingError::AntennaOutOfRange(msg.antenna))?;
+        let rssi = i8::try_from(msg.rssi)
+            .map_err(|_| CSIReadingError::RssiOutOfRange(msg.rssi))?;
+
+        // noise_floor is a single byte on the sensor, which may arrive sign-extended or not
+        if !(i8::MIN as i32 ..= u8::MAX as i32).contains(&msg.noise_floor) {
+            return Err(CSIReadingError::NoiseFloorOutOfRange(msg.noise_floor))
+        }
+        let noise_floor = i32::from(msg.noise_floor as i8);
+        let sequence_identifier = msg.sequence_identifier;
 
-        let antenna = i8::try_from(msg.antenna).unwrap();
-        let rssi = i8::try_from(msg.rssi).unwrap();
-        let noise_floor = i32::try_from(msg.noise_floor as i8).unwrap();
-        let sequence_identifier = i32::try_from(msg.sequence_identifier).unwrap();
+        let mac_address = MacAddress::try_from(msg.src_mac.as_slice())?;
+        let mac = mac_address.tag();
+        // filled in from the sensor registry by the handler
+        let unregistered = SensorInfo::unregistered();
 
-        let mac = format!("{:X}{:X}{:X}", msg.src_mac.clone()[3], msg.src_mac.clone()[4], msg.src_mac.clone()[5]);
+        // verified later on according to the configured crc_policy
+        let crc_valid = true;
 
         let interval = 1;
-        let correlation_coefficient = 0.0;
+        let correlation_coefficient = None;
 
-        let csi_matrix = get_csi_matrix(msg).unwrap();
+        let (csi_complex, subcarrier_indices) = layout::get().extract(&mac_address, &msg.csi_data)?;
+        let scaling = config::get().lock().unwrap().csi.scaling;
+        let csi_matrix = get_csi_matrix(&csi_complex, rssi, noise_floor, scaling);
+        let phase = sanitise_phase(&csi_complex, &subcarrier_indices);
 
-        Self {
-            time,
+        Ok(Self {
             antenna,
             rssi,
             noise_floor,
             correlation_coefficient,
             mac,
+            mac_address,
+            sensor_name: unregistered.name,
+            room: unregistered.room,
+            zone: unregistered.zone,
+            role: unregistered.role,
             sequence_identifier,
             interval,
+            crc_valid,
+            crc_mismatches: 0,
             csi_matrix,
+            csi_complex,
+            phase,
             timestamp_us
+        })
+    }
+}
+
+impl CSIReading {
+    // linear amplitude per subcarrier, unlike csi_matrix which is scaled and in dB
+    pub fn amplitude(&self) -> Array<f32, Ix2> {
+        self.csi_complex.mapv(|h| h.norm())
+    }
+
+    pub fn to_record(&self, measurement: &str) -> Record {
+        let mut record = Record::new(measurement, self.timestamp_us)
+            .add_field("rssi", self.rssi)
+            .add_field("noise_floor", self.noise_floor);
+        if let Some(pcc) = self.correlation_coefficient {
+            record = record.add_field("correlation_coefficient", pcc);
         }
+        let record = record
+            .add_field("sequence_identifier", self.sequence_identifier)
+            .add_field("interval", self.interval)
+            .add_field("crc_valid", self.crc_valid)
+            .add_field("crc_mismatches", self.crc_mismatches);
+        self.add_tags(record)
+    }
+
+    pub fn add_tags(&self, record: Record) -> Record {
+        record
+            .add_tag("mac", &self.mac)
+            .add_tag("antenna", self.antenna)
+            .add_tag("sensor_name", &self.sensor_name)
+            .add_tag("room", &self.room)
+            .add_tag("zone", &self.zone)
+            .add_tag("role", &self.role)
+    }
+
+    pub fn set_sensor(&mut self, sensor: &SensorInfo) {
+        self.sensor_name = sensor.name.clone();
+        self.room = sensor.room.clone();
+        self.zone = sensor.zone.clone();
+        self.role = sensor.role.clone();
     }
 }
 
-pub fn parse_csi_protobuf(expected_protobuf: &[u8]) -> Result<CsiMessage, DecodeError>  {
-    match CsiMessage::decode(expected_protobuf) {
-        Ok(t) => Ok(t),
-        Err(e) => Err(e),
+// csi_crc32 is optional, sensors which don't compute it leave it at 0
+pub fn verify_crc(msg: &CsiMessage) -> Result<(), CSIReadingError> {
+    if msg.csi_crc32 == 0 {
+        return Ok(())
     }
+
+    let computed_crc32 = crc32fast::hash(&msg.csi_data);
+    if computed_crc32 != msg.csi_crc32 {
+        return Err(CSIReadingError::CRCMismatch(msg.csi_crc32, computed_crc32))
+    }
+    Ok(())
+}
+
+pub fn parse_csi_protobuf(expected_protobuf: &[u8]) -> Result<CsiMessage, DecodeError>  {
+    CsiMessage::decode(expected_protobuf)
 }
 
-fn get_csi_matrix(msg: &CsiMessage) -> Result<Array<f32, Ix2>, RecvMessageError> {
-    let csi_data = msg.csi_data.clone();
+// i/q are whole numbers, so a subcarrier with any signal has an amplitude of at least 1.
+// empty ones are put half a step below that rather than at -inf, which would break the
+// correlation, and stay below every real value of the frame whatever its scaling
+const EMPTY_AMPLITUDE: f32 = 0.5;
 
-    let mut csi_matrix = Array::zeros((1, ACTIVE_SUBCARRIERS));
+fn floored_amplitude(h: &Complex32) -> f32 {
+    h.norm().max(EMPTY_AMPLITUDE)
+}
 
-    for (dest, src) in REQUIRED_SUBCARRIERS.into_iter().enumerate() {
-        //print!("{:?}", csi_data);
-        let imag = csi_data[src * 2] as i8 as f32;
-        let real = csi_data[src * 2 + 1] as i8 as f32;
+// per subcarrier power in dB, after scaling the channel response according to csi.scaling
+fn get_csi_matrix(csi_complex: &Array<Complex32, Ix2>, rssi: i8, noise_floor: i32, scaling: Scaling) -> Array<f32, Ix2> {
+    let factor = get_scaling_factor(csi_complex, rssi, noise_floor, scaling);
 
-        let sum_of_squares = imag.powi(2) + real.powi(2);
-        let norm = sum_of_squares.sqrt();
+    csi_complex.mapv(|h| 20_f32 * (floored_amplitude(&h) * factor).log10())
+}
 
-        if norm == 0.0 {
-            csi_matrix[[0, dest]] = norm;
-        } else {
-            let db_val = 20 as f32 * norm.log10();
-            csi_matrix[[0, dest]] = db_val;
-        }
-    }
+// raw csi phase is dominated by a slope across subcarriers from symbol timing offset and a
+// constant from carrier frequency offset, both of which change every frame. unwrap the
+// phase in frequency order, then subtract the least squares line through it.
+pub fn sanitise_phase(csi_complex: &Array<Complex32, Ix2>, subcarrier_indices: &[i32]) -> Array<f32, Ix2> {
+    let mut order: Vec<usize> = (0..subcarrier_indices.len()).collect();
+    order.sort_by_key(|&i| subcarrier_indices[i]);
 
-    //print!("{:?}", csi_matrix);
+    let mut phase = Array::zeros(csi_complex.raw_dim());
+    for (row, mut phase_row) in csi_complex.rows().into_iter().zip(phase.rows_mut()) {
+        let mut unwrapped = vec![0_f32; order.len()];
+        let mut previous: Option<f32> = None;
+        for (n, &i) in order.iter().enumerate() {
+            let mut p = row[i].arg();
+            if let Some(prev) = previous {
+                p -= 2.0 * PI * ((p - prev) / (2.0 * PI)).round();
+            }
+            unwrapped[n] = p;
+            previous = Some(p);
+        }
 
-    let mut filtered_csi_matrix = Array::zeros((1, ACTIVE_SUBCARRIERS));
-    let scaling_factor: f32 = get_scaling_factor(&csi_matrix, msg.rssi.clone());
+        let k: Vec<f32> = order.iter().map(|&i| subcarrier_indices[i] as f32).collect();
+        let count = k.len() as f32;
+        let k_mean = k.iter().sum::<f32>() / count;
+        let p_mean = unwrapped.iter().sum::<f32>() / count;
+        let covariance: f32 = k.iter().zip(&unwrapped).map(|(k, p)| (k - k_mean) * (p - p_mean)).sum();
+        let variance: f32 = k.iter().map(|k| (k - k_mean).powi(2)).sum();
+        let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
 
-    for n in 1..ACTIVE_SUBCARRIERS {
-        // filtered_csi_matrix[[0, n]] = csi_matrix[[0, n]];
-        filtered_csi_matrix[[0, n]] = csi_matrix[[0, n]] * scaling_factor.sqrt();
+        for (n, &i) in order.iter().enumerate() {
+            phase_row[i] = unwrapped[n] - p_mean - slope * (k[n] - k_mean);
+        }
     }
 
-    Ok(filtered_csi_matrix)
+    phase
 }
 
-pub fn get_correlation_coefficient(frame: Array<f32, Ix2>, frame2: &Array<f32, Ix2>) -> f32 {
-    let stacked = concatenate(Axis(0), &[frame2.view(), frame.view()]).unwrap();
-    let corr = stacked.pearson_correlation().unwrap();
+// None when the frames can't be compared, e.g. across a layout change or when either
+// has no variation across its subcarriers
+pub fn get_correlation_coefficient(frame: &Array<f32, Ix2>, frame2: &Array<f32, Ix2>) -> Option<f32> {
+    if frame.raw_dim() != frame2.raw_dim() {
+        return None
+    }
+    let stacked = concatenate(Axis(0), &[frame2.view(), frame.view()]).ok()?;
+    let corr = stacked.pearson_correlation().ok()?;
 
-    corr[[1, 0]]
+    Some(corr[[1, 0]]).filter(|pcc| pcc.is_finite())
 }
 
-pub fn get_scaling_factor(mag_vals: &Array<f32, Ix2>, rssi: i32) -> f32 {
-    let rssi_pwr = 10_f32.powi(rssi / 10);
-    // println!("Scaling CSIMeasurement CSI with RSSI_pwr {:?}", rssi_pwr);
-    let vec_mag = mag_vals.iter().map(|x| x.powi(2)).sum::<f32>();
-    // println!("Scaling opwedqqqwd {:?}", mag_vals);
-    let norm_vec_mag = vec_mag / 64_f32;
+fn dbm_to_mw(dbm: f32) -> f32 {
+    10_f32.powf(dbm / 10.0)
+}
+
+// amplitude factor which makes the mean power across subcarriers equal the received power.
+// the agc scales every frame to fill the adc, so raw amplitudes only compare between frames
+// and sensors once scaled back by rssi. sensors with a fixed gain should use none.
+pub fn get_scaling_factor(csi_complex: &Array<Complex32, Ix2>, rssi: i8, noise_floor: i32, scaling: Scaling) -> f32 {
+    let power_mw = match scaling {
+        Scaling::None => return 1.0,
+        // rssi includes the noise, so subtract it to leave the signal power. a frame at or
+        // below the noise floor would have none left, so it's scaled by its rssi instead
+        Scaling::Snr if i32::from(rssi) > noise_floor => dbm_to_mw(rssi as f32) - dbm_to_mw(noise_floor as f32),
+        Scaling::Rssi | Scaling::Snr => dbm_to_mw(rssi as f32),
+    };
 
-    rssi_pwr / norm_vec_mag
+    // over the amplitudes as they end up in csi_matrix, so never 0
+    let mean_power = csi_complex.iter().map(|h| floored_amplitude(h).powi(2)).sum::<f32>() / csi_complex.len().max(1) as f32;
+
+    (power_mw / mean_power).sqrt()
 }
 
 pub fn get_reading(msg: &CsiMessage) -> Result<CSIReading, RecvMessageError> {
-    Ok(CSIReading::new(msg))
+    Ok(CSIReading::try_from(msg)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    // powers of 400, 100, 100 and 200, so a mean of 200
+    fn frame() -> Array<Complex32, Ix2> {
+        array![[Complex32::new(20.0, 0.0), Complex32::new(0.0, 10.0), Complex32::new(10.0, 0.0), Complex32::new(10.0, 10.0)]]
+    }
+
+    fn assert_close(actual: &Array<f32, Ix2>, expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-3, "{} != {} in {:?}", a, e, actual);
+        }
+    }
+
+    #[test]
+    fn no_scaling_is_raw_amplitude_in_db() {
+        assert_eq!(get_scaling_factor(&frame(), -30, -40, Scaling::None), 1.0);
+        assert_close(&get_csi_matrix(&frame(), -30, -40, Scaling::None), &[26.0206, 20.0, 20.0, 23.0103]);
+    }
+
+    #[test]
+    fn rssi_scaling_makes_the_mean_power_the_rssi() {
+        // sqrt(1e-3 mW / 200)
+        let factor = get_scaling_factor(&frame(), -30, -40, Scaling::Rssi);
+        assert!((factor - 2.236068e-3).abs() < 1e-8, "{}", factor);
+        // -30 dBm plus each subcarrier's power relative to the mean
+        assert_close(&get_csi_matrix(&frame(), -30, -40, Scaling::Rssi), &[-26.9897, -33.0103, -33.0103, -30.0]);
+    }
+
+    #[test]
+    fn snr_scaling_subtracts_the_noise_floor() {
+        // sqrt((1e-3 - 1e-4) mW / 200)
+        let factor = get_scaling_factor(&frame(), -30, -40, Scaling::Snr);
+        assert!((factor - 2.12132e-3).abs() < 1e-8, "{}", factor);
+        // 9e-4 mW is -30.4576 dBm
+        assert_close(&get_csi_matrix(&frame(), -30, -40, Scaling::Snr), &[-27.4473, -33.4679, -33.4679, -30.4576]);
+    }
+
+    #[test]
+    fn snr_scaling_falls_back_to_rssi_at_the_noise_floor() {
+        for (rssi, noise_floor) in [(-95, -92), (-92, -92)] {
+            let snr = get_scaling_factor(&frame(), rssi, noise_floor, Scaling::Snr);
+            assert!(snr > 0.0);
+            assert_eq!(snr, get_scaling_factor(&frame(), rssi, noise_floor, Scaling::Rssi));
+        }
+        assert_close(&get_csi_matrix(&frame(), -95, -92, Scaling::Snr), &[-91.9897, -98.0103, -98.0103, -95.0]);
+    }
+
+    #[test]
+    fn empty_subcarriers_are_floored_below_the_rest() {
+        let csi = array![[Complex32::new(3.0, 4.0), Complex32::new(0.0, 0.0), Complex32::new(0.0, 1.0), Complex32::new(0.0, 0.0)]];
+        assert_close(&get_csi_matrix(&csi, -30, -40, Scaling::None), &[13.9794, -6.0206, 0.0, -6.0206]);
+
+        let scaled = get_csi_matrix(&csi, -30, -40, Scaling::Rssi);
+        assert!(scaled[[0, 1]] < scaled[[0, 2]] && scaled[[0, 3]] < scaled[[0, 2]]);
+    }
+
+    #[test]
+    fn empty_frames_stay_finite() {
+        let csi = Array::zeros((1, 4));
+        assert_close(&get_csi_matrix(&csi, -40, -92, Scaling::Rssi), &[-40.0; 4]);
+    }
+
+    // a phase of offset + slope * k on each subcarrier, with amplitudes that vary
+    fn ramp(indices: &[i32], slope: f32, offset: f32) -> Array<Complex32, Ix2> {
+        let row: Vec<Complex32> = indices.iter()
+            .map(|&k| Complex32::from_polar(10.0 + k.abs() as f32, offset + slope * k as f32))
+            .collect();
+        Array::from_shape_vec((1, row.len()), row).unwrap()
+    }
+
+    #[test]
+    fn sanitising_removes_a_wrapped_linear_phase() {
+        let indices: Vec<i32> = (-26..=-1).chain(1..=26).collect();
+        // spans about 20 radians, so it wraps past +-pi several times
+        let csi = ramp(&indices, 0.4, 2.5);
+        assert!(csi.iter().any(|h| h.arg() < -3.0) && csi.iter().any(|h| h.arg() > 3.0));
+        assert_close(&sanitise_phase(&csi, &indices), &[0.0; 52]);
+    }
+
+    #[test]
+    fn sanitising_unwraps_in_frequency_order() {
+        // buffer order as in the lltf, with a few swapped for good measure
+        let mut indices: Vec<i32> = (1..=26).chain(-26..=-1).collect();
+        indices.swap(3, 40);
+        indices.swap(10, 20);
+        let mut csi = ramp(&indices, -0.3, -1.0);
+
+        // a bump on one subcarrier is all that's left, less its share of the mean
+        let bumped = indices.iter().position(|&k| k == 5).unwrap();
+        csi[[0, bumped]] *= Complex32::from_polar(1.0, 0.52);
+        let phase = sanitise_phase(&csi, &indices);
+        assert!((phase[[0, bumped]] - 0.51).abs() < 0.01, "{}", phase[[0, bumped]]);
+        let mut others = phase.iter().enumerate().filter(|&(i, _)| i != bumped);
+        assert!(others.all(|(_, p)| p.abs() < 0.02), "{:?}", phase);
+    }
 }
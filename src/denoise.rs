@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+
+use ndarray::{Array, Ix2};
+use sci_rs::signal::filter::design::{butter_dyn, DigitalFilter, FilterOutputType, Sos};
+use sci_rs::signal::filter::{sosfilt_item, sosfilt_zi_dyn};
+
+use crate::config::{self, FilterStage};
+
+// scales the median absolute deviation to a standard deviation for normal data
+const MAD_SCALE: f32 = 1.4826;
+
+enum Stage {
+    Hampel { window: usize, threshold: f32, history: Vec<VecDeque<f32>> },
+    Butterworth { design: Vec<Sos<f32>>, sections: Vec<Vec<Sos<f32>>> },
+    SavitzkyGolay { coefficients: Vec<f32>, history: Vec<VecDeque<f32>> },
+    DcRemoval { alpha: f32, mean: Vec<f32> },
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] }
+}
+
+// weights over the last window readings, oldest first, of a least squares polynomial
+// fit evaluated at the newest reading
+fn savitzky_golay_coefficients(window: usize, order: usize) -> Vec<f32> {
+    let terms = order + 1;
+    let x: Vec<f64> = (0..window).map(|i| i as f64 - (window - 1) as f64).collect();
+    let powers = |x: f64| (0..terms).map(move |j| x.powi(j as i32));
+
+    // normal equations, augmented with e0 since only the constant term is wanted
+    let mut m = vec![vec![0_f64; terms + 1]; terms];
+    for &xi in &x {
+        let p: Vec<f64> = powers(xi).collect();
+        for (j, row) in m.iter_mut().enumerate() {
+            for k in 0..terms {
+                row[k] += p[j] * p[k];
+            }
+        }
+    }
+    m[0][terms] = 1.0;
+
+    // gauss-jordan with partial pivoting, the system is tiny
+    for col in 0..terms {
+        let pivot = (col..terms).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs())).unwrap();
+        m.swap(col, pivot);
+        let divisor = m[col][col];
+        for value in m[col].iter_mut() {
+            *value /= divisor;
+        }
+        for row in 0..terms {
+            if row != col {
+                let factor = m[row][col];
+                let pivot_row = m[col].clone();
+                for (value, p) in m[row].iter_mut().zip(pivot_row) {
+                    *value -= factor * p;
+                }
+            }
+        }
+    }
+    let v: Vec<f64> = m.iter().map(|row| row[terms]).collect();
+
+    x.iter().map(|&xi| powers(xi).zip(&v).map(|(p, v)| p * v).sum::<f64>() as f32).collect()
+}
+
+impl Stage {
+    fn new(stage: &FilterStage, rate_hz: f64) -> Self {
+        match *stage {
+            FilterStage::Hampel { window, threshold } => Stage::Hampel { window, threshold, history: Vec::new() },
+            FilterStage::Butterworth { order, cutoff_hz } => {
+                let filter = butter_dyn(order, vec![cutoff_hz as f32], None, Some(false), Some(FilterOutputType::Sos), Some(rate_hz as f32));
+                let DigitalFilter::Sos(filter) = filter else {
+                    unreachable!("butter_dyn returns the requested output type")
+                };
+                Stage::Butterworth { design: filter.sos, sections: Vec::new() }
+            }
+            FilterStage::SavitzkyGolay { window, order } => Stage::SavitzkyGolay {
+                coefficients: savitzky_golay_coefficients(window, order),
+                history: Vec::new(),
+            },
+            FilterStage::DcRemoval { window } => Stage::DcRemoval { alpha: 1.0 / window as f32, mean: Vec::new() },
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            Stage::Hampel { history, .. } | Stage::SavitzkyGolay { history, .. } => history.len(),
+            Stage::Butterworth { sections, .. } => sections.len(),
+            Stage::DcRemoval { mean, .. } => mean.len(),
+        }
+    }
+
+    // per subcarrier state starts from the first values this stage sees
+    fn reset(&mut self, first: &[f32]) {
+        match self {
+            Stage::Hampel { history, .. } | Stage::SavitzkyGolay { history, .. } => {
+                *history = vec![VecDeque::new(); first.len()];
+            }
+            Stage::Butterworth { design, sections } => {
+                // start in the steady state for the first value rather than ringing up from 0
+                *sections = first.iter().map(|&x| {
+                    let mut s = design.clone();
+                    sosfilt_zi_dyn::<f32, _, Sos<f32>>(s.iter_mut());
+                    for section in &mut s {
+                        section.zi0 *= x;
+                        section.zi1 *= x;
+                    }
+                    s
+                }).collect();
+            }
+            Stage::DcRemoval { mean, .. } => *mean = first.to_vec(),
+        }
+    }
+
+    fn apply(&mut self, values: &mut [f32]) {
+        // a layout change means different subcarriers, so start over
+        if self.width() != values.len() {
+            self.reset(values);
+        }
+
+        match self {
+            Stage::Hampel { window, threshold, history } => {
+                for (value, history) in values.iter_mut().zip(history) {
+                    if history.len() == *window {
+                        history.pop_front();
+                    }
+                    history.push_back(*value);
+                    if history.len() < 3 {
+                        continue
+                    }
+
+                    let mut window: Vec<f32> = history.iter().copied().collect();
+                    let m = median(&mut window);
+                    let mut deviations: Vec<f32> = window.iter().map(|v| (v - m).abs()).collect();
+                    let mad = median(&mut deviations);
+                    if (*value - m).abs() > *threshold * MAD_SCALE * mad {
+                        *value = m;
+                    }
+                }
+            }
+            Stage::Butterworth { sections, .. } => {
+                for (value, sections) in values.iter_mut().zip(sections) {
+                    *value = sosfilt_item(*value, sections);
+                }
+            }
+            Stage::SavitzkyGolay { coefficients, history } => {
+                for (value, history) in values.iter_mut().zip(history) {
+                    if history.len() == coefficients.len() {
+                        history.pop_front();
+                    }
+                    history.push_back(*value);
+                    // not enough readings to fit yet
+                    if history.len() == coefficients.len() {
+                        *value = history.iter().zip(coefficients.iter()).map(|(v, c)| v * c).sum();
+                    }
+                }
+            }
+            Stage::DcRemoval { alpha, mean } => {
+                for (value, mean) in values.iter_mut().zip(mean) {
+                    *mean += *alpha * (*value - *mean);
+                    *value -= *mean;
+                }
+            }
+        }
+    }
+}
+
+// the denoise chain of one link, applied to each subcarrier of csi_matrix in turn
+pub struct FilterChain {
+    stages: Vec<Stage>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        let config = config::get().lock().unwrap().denoise.clone();
+        Self {
+            stages: config.chain.iter().map(|stage| Stage::new(stage, config.rate_hz)).collect(),
+        }
+    }
+
+    pub fn apply(&mut self, csi_matrix: &Array<f32, Ix2>) -> Array<f32, Ix2> {
+        let mut filtered = csi_matrix.clone();
+        if self.stages.is_empty() {
+            return filtered
+        }
+
+        for mut row in filtered.rows_mut() {
+            let mut values = row.to_vec();
+            for stage in &mut self.stages {
+                stage.apply(&mut values);
+            }
+            row.assign(&Array::from(values));
+        }
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    fn chain(stages: &[FilterStage]) -> FilterChain {
+        FilterChain { stages: stages.iter().map(|stage| Stage::new(stage, 20.0)).collect() }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-4, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn median_of_odd_and_even_lengths() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
+
+    #[test]
+    fn savitzky_golay_matches_scipy() {
+        // scipy.signal.savgol_coeffs(5, 2, pos=4), reversed to oldest first
+        let expected: Vec<f32> = [3.0, -5.0, -3.0, 9.0, 31.0].iter().map(|c| c / 35.0).collect();
+        assert_close(&savitzky_golay_coefficients(5, 2), &expected);
+        // a straight line fit through 3 points
+        assert_close(&savitzky_golay_coefficients(3, 1), &[-1.0 / 6.0, 1.0 / 3.0, 5.0 / 6.0]);
+    }
+
+    #[test]
+    fn savitzky_golay_keeps_polynomials_of_its_order() {
+        let mut chain = chain(&[FilterStage::SavitzkyGolay { window: 5, order: 2 }]);
+        let outputs: Vec<f32> = (0..8).map(|i| {
+            let x = i as f32;
+            chain.apply(&array![[x * x - 2.0 * x]])[[0, 0]]
+        }).collect();
+        let inputs: Vec<f32> = (0..8).map(|i| (i * i - 2 * i) as f32).collect();
+        assert_close(&outputs, &inputs);
+    }
+
+    #[test]
+    fn hampel_replaces_outliers_with_the_median() {
+        let mut chain = chain(&[FilterStage::Hampel { window: 5, threshold: 3.0 }]);
+        for value in [1.0, 2.0, 1.0, 2.0] {
+            assert_eq!(chain.apply(&array![[value]])[[0, 0]], value);
+        }
+        assert_eq!(chain.apply(&array![[40.0]])[[0, 0]], 2.0);
+    }
+
+    #[test]
+    fn dc_removal_converges_to_zero() {
+        let mut chain = chain(&[FilterStage::DcRemoval { window: 4 }]);
+        assert_eq!(chain.apply(&array![[5.0]])[[0, 0]], 0.0);
+        // a step is followed by a decay of 3/4 per reading
+        let outputs: Vec<f32> = (0..3).map(|_| chain.apply(&array![[9.0]])[[0, 0]]).collect();
+        assert_close(&outputs, &[3.0, 2.25, 1.6875]);
+    }
+
+    #[test]
+    fn butterworth_passes_a_constant() {
+        let mut chain = chain(&[FilterStage::Butterworth { order: 4, cutoff_hz: 1.0 }]);
+        for _ in 0..20 {
+            assert_close(chain.apply(&array![[7.0, -3.0]]).as_slice().unwrap(), &[7.0, -3.0]);
+        }
+    }
+
+    #[test]
+    fn layout_changes_restart_each_stage() {
+        let mut chain = chain(&[FilterStage::SavitzkyGolay { window: 3, order: 1 }]);
+        for _ in 0..3 {
+            chain.apply(&array![[0.0, 0.0]]);
+        }
+        assert_close(chain.apply(&array![[6.0, 6.0]]).as_slice().unwrap(), &[5.0, 5.0]);
+        // the history is gone so nothing is smoothed until the window fills again
+        assert_close(chain.apply(&array![[6.0, 6.0, 6.0]]).as_slice().unwrap(), &[6.0, 6.0, 6.0]);
+    }
+
+    #[test]
+    fn an_empty_chain_changes_nothing() {
+        let csi = array![[1.0, 2.0], [3.0, 4.0]];
+        assert_eq!(chain(&[]).apply(&csi), csi);
+    }
+}
@@ -1,34 +1,183 @@
-// use std::net::SocketAddr;
+use std::net::SocketAddr;
 use prost::DecodeError;
 
+use crate::mac::MacAddress;
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum RecvMessageError {
-    // #[error("socket.recv_from returned error.")]
-    // SocketRecvError(),
+    #[error("socket.recv_from returned error: {0}")]
+    SocketRecvError(#[from] std::io::Error),
+
+    #[error("Message decompression failed: {0}")]
+    MessageDecompressionError(String),
+
+    #[error("Received empty message from {0}.")]
+    EmptyMessageError(SocketAddr),
+
+    #[error("Could not determine format ({0}) for incoming message from {1} with size: {2}.")]
+    MessageFormatDecodeError(u8, SocketAddr, usize),
+
+    #[error("Compressed container is truncated (expected {0} bytes, got {1}).")]
+    ContainerTruncatedError(usize, usize),
+
+    #[error("Decompressed container exceeds the limit of {0} bytes.")]
+    ContainerSizeExceededError(usize),
+
+    #[error("Could not determine the number of frames in compressed container with size: {0} (frame size: {1}).")]
+    ContainerFrameCountError(usize, usize),
 
-    #[error("Message decompression failed.")]
-    MessageDecompressionError(),
+    #[error("Frame {0} in compressed container declares a length of {1} (max: {2}).")]
+    ContainerFrameLengthError(usize, usize, usize),
 
-    // #[error("Could not determine format ({0}) for incoming message from {1} with size: {2}.")]
-    // MessageFormatDecodeError(u8, SocketAddr, usize),
+    #[error("All {0} frames in compressed container were discarded.")]
+    ContainerDiscardedError(usize),
     //
     // #[error("Could not determine type for incoming Message.")]
     // MessageTypeDecodeError(),
     //
     #[error("Failed to parse protobuf from buffer contents.")]
     ProtobufParseError(#[from] DecodeError),
-    //
-    // #[error("Failed to build CSIReading from protobuf:")]
-    // CSIReadingGenerateError(),
-    //
-    // #[error("Failed to build CSIReading from protobuf:")]
-    // TelemetryReadingGenerateError(),
-    //
-    // #[error("Failed to generate a valid CSI matrix from protobuf:")]
-    // CSIMatrixParseError(),
+
+    #[error("Failed to build CSIReading from protobuf: {0}")]
+    CSIReadingGenerateError(CSIReadingError),
+
+    #[error("Failed to build TelemetryReading from protobuf: {0}")]
+    TelemetryReadingGenerateError(#[from] TelemetryReadingError),
+
+    #[error("Failed to generate a valid CSI matrix from protobuf: {0}")]
+    CSIMatrixParseError(CSIReadingError),
+
+    #[error("Rejected reading from unregistered sensor {0}.")]
+    UnregisteredSensorError(MacAddress),
+
+    #[error("Rejected reading from filtered sensor {0}.")]
+    SensorFilteredError(MacAddress),
+
+    #[error("Failed to write batch to {0} sink: {1}")]
+    SinkWriteError(&'static str, SinkError),
     //
     // #[error("Failed to calculate PCC for given frames.")]
     // PCCCalcError(),
-}
\ No newline at end of file
+}
+
+
+#[derive(Error, Debug)]
+pub enum CSIReadingError {
+    #[error("timestamp ({0}) is negative.")]
+    NegativeTimestamp(i64),
+
+    #[error("src_mac is invalid: {0}")]
+    InvalidMac(#[from] MacAddressError),
+
+    #[error("antenna ({0}) is out of range.")]
+    AntennaOutOfRange(u32),
+
+    #[error("rssi ({0}) is out of range.")]
+    RssiOutOfRange(i32),
+
+    #[error("noise_floor ({0}) is out of range.")]
+    NoiseFloorOutOfRange(i32),
+
+    #[error("csi_data has length {0}, expected {1}.")]
+    InvalidCSILength(usize, usize),
+
+    #[error("no subcarrier layout for csi_data of length {0}.")]
+    UnknownCSILayout(usize),
+
+    #[error("csi_crc32 mismatch (received: {0:#010x}, computed: {1:#010x}).")]
+    CRCMismatch(u32, u32),
+}
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ConfigError {
+    #[error("Could not read config file `{0}`: {1}")]
+    ReadError(String, std::io::Error),
+
+    #[error("Unable to parse config file `{0}`: {1}")]
+    ParseError(String, toml::de::Error),
+
+    #[error("Environment variable {0} can't override `{1}`, a parent key is not a table.")]
+    OverrideError(String, String),
+
+    #[error("Invalid config key `{0}` (from {1}): {2}")]
+    InvalidKeyError(String, String, String),
+
+    #[error("Invalid config key `{0}`: {1}")]
+    ValidationError(String, String),
+}
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum InfluxError {
+    #[error("Could not build line protocol: {0}")]
+    LineProtocolError(#[from] influxdb::Error),
+
+    #[error("Could not reach InfluxDB: {0}")]
+    ConnectionError(#[from] reqwest::Error),
+
+    #[error("InfluxDB responded with {0}: {1}")]
+    ResponseError(reqwest::StatusCode, String),
+}
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum SinkError {
+    #[error("{0}")]
+    InfluxWriteError(#[from] InfluxError),
+
+    #[error("Could not write to file: {0}")]
+    FileWriteError(#[from] std::io::Error),
+
+    #[error("Batch watcher failed: {0}")]
+    WatcherError(#[from] tokio::task::JoinError),
+}
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ReplayError {
+    #[error("Could not read `{0}`: {1}")]
+    ReadError(String, std::io::Error),
+
+    #[error("Invalid archive record in `{0}`: {1}")]
+    ArchiveDecodeError(String, DecodeError),
+
+    #[error("Invalid pcap file `{0}`: {1}")]
+    PcapFormatError(String, String),
+
+    #[error("Record in `{0}` declares a length of {1} bytes (max: {2}).")]
+    RecordLengthError(String, u64, usize),
+
+    #[error("`{0}` is neither a pcap file nor a CSI archive.")]
+    UnknownFormatError(String),
+}
+
+#[derive(Error, Debug)]
+pub enum TelemetryReadingError {
+    #[error("timestamp ({0}) is negative.")]
+    NegativeTimestamp(i64),
+
+    #[error("device_mac is invalid: {0}")]
+    InvalidMac(#[from] MacAddressError),
+}
+
+#[derive(Error, Debug)]
+pub enum MacAddressError {
+    #[error("MAC address has length {0}, expected 6.")]
+    InvalidLength(usize),
+
+    #[error("Could not parse MAC address `{0}`.")]
+    ParseError(String),
+}
+
+impl From<CSIReadingError> for RecvMessageError {
+    fn from(e: CSIReadingError) -> Self {
+        match e {
+            CSIReadingError::InvalidCSILength(..) | CSIReadingError::UnknownCSILayout(_) => RecvMessageError::CSIMatrixParseError(e),
+            _ => RecvMessageError::CSIReadingGenerateError(e),
+        }
+    }
+}
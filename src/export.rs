@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use num::complex::Complex32;
+
+use crate::config;
+use crate::csi::CSIReading;
+use crate::writer::{self, Queue, Writer};
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+// magic, version and header length prefix, plus room for any shape we could write
+const NPY_HEADER_LEN: usize = 128;
+// how often row counts are written back to the npy headers, so files stay loadable while open
+const HEADER_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// one row of a link's export
+struct ExportRow {
+    key: String,
+    timestamp_us: i64,
+    rssi: i8,
+    sequence_identifier: i32,
+    csi: Vec<f32>,
+    csi_complex: Vec<Complex32>,
+    amplitude: Vec<f32>,
+    phase: Vec<f32>,
+}
+
+// the handler side of the export, rows are queued here and written by the export thread
+pub struct Exporter {
+    queue: Queue<ExportRow>,
+}
+
+impl Exporter {
+    pub fn record(&self, reading: &CSIReading) {
+        let row = ExportRow {
+            key: format!("{}-{}", reading.mac_address.to_string().replace(':', ""), reading.antenna),
+            timestamp_us: reading.timestamp_us as i64,
+            rssi: reading.rssi,
+            sequence_identifier: reading.sequence_identifier,
+            csi: reading.csi_matrix.iter().copied().collect(),
+            csi_complex: reading.csi_complex.iter().copied().collect(),
+            amplitude: reading.amplitude().iter().copied().collect(),
+            phase: reading.phase.iter().copied().collect(),
+        };
+        self.queue.send(row);
+    }
+}
+
+// an npy file which is appended to a row at a time. the header is padded to a fixed
+// length so it can be rewritten in place as the row count grows.
+struct NpyWriter {
+    file: BufWriter<File>,
+    descr: &'static str,
+    // None for 1-d arrays
+    columns: Option<usize>,
+    rows: u64,
+}
+
+impl NpyWriter {
+    fn create(path: &Path, descr: &'static str, columns: Option<usize>) -> io::Result<Self> {
+        let mut npy = Self {
+            file: BufWriter::new(File::create(path)?),
+            descr,
+            columns,
+            rows: 0,
+        };
+        npy.write_header()?;
+        Ok(npy)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let shape = match self.columns {
+            Some(columns) => format!("({}, {})", self.rows, columns),
+            None => format!("({},)", self.rows),
+        };
+        let dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", self.descr, shape);
+
+        let mut header = Vec::with_capacity(NPY_HEADER_LEN);
+        header.extend_from_slice(NPY_MAGIC);
+        header.extend_from_slice(&((NPY_HEADER_LEN - NPY_MAGIC.len() - 2) as u16).to_le_bytes());
+        header.extend_from_slice(dict.as_bytes());
+        header.resize(NPY_HEADER_LEN - 1, b' ');
+        header.push(b'\n');
+        header
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let header = self.header();
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+fn f32_bytes(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+struct LinkExport {
+    csi: NpyWriter,
+    csi_complex: NpyWriter,
+    amplitude: NpyWriter,
+    phase: NpyWriter,
+    timestamp_us: NpyWriter,
+    rssi: NpyWriter,
+    sequence_identifier: NpyWriter,
+    // rows whose width didn't match the first row's
+    mismatched: u64,
+}
+
+impl LinkExport {
+    fn create(dir: &Path, columns: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            csi: NpyWriter::create(&dir.join("csi.npy"), "<f4", Some(columns))?,
+            csi_complex: NpyWriter::create(&dir.join("csi_complex.npy"), "<c8", Some(columns))?,
+            amplitude: NpyWriter::create(&dir.join("amplitude.npy"), "<f4", Some(columns))?,
+            phase: NpyWriter::create(&dir.join("phase.npy"), "<f4", Some(columns))?,
+            timestamp_us: NpyWriter::create(&dir.join("timestamp_us.npy"), "<i8", None)?,
+            rssi: NpyWriter::create(&dir.join("rssi.npy"), "|i1", None)?,
+            sequence_identifier: NpyWriter::create(&dir.join("sequence_identifier.npy"), "<i4", None)?,
+            mismatched: 0,
+        })
+    }
+
+    fn append(&mut self, row: &ExportRow) -> io::Result<()> {
+        self.csi.append(&f32_bytes(&row.csi))?;
+        let csi_complex: Vec<f32> = row.csi_complex.iter().flat_map(|h| [h.re, h.im]).collect();
+        self.csi_complex.append(&f32_bytes(&csi_complex))?;
+        self.amplitude.append(&f32_bytes(&row.amplitude))?;
+        self.phase.append(&f32_bytes(&row.phase))?;
+        self.timestamp_us.append(&row.timestamp_us.to_le_bytes())?;
+        self.rssi.append(&row.rssi.to_le_bytes())?;
+        self.sequence_identifier.append(&row.sequence_identifier.to_le_bytes())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let files = [
+            &mut self.csi, &mut self.csi_complex, &mut self.amplitude, &mut self.phase,
+            &mut self.timestamp_us, &mut self.rssi, &mut self.sequence_identifier,
+        ];
+        for npy in files {
+            npy.write_header()?;
+        }
+        Ok(())
+    }
+}
+
+struct ExportWriter {
+    session_dir: PathBuf,
+    links: HashMap<String, LinkExport>,
+    dirty: bool,
+    last_sync: Instant,
+}
+
+impl Writer for ExportWriter {
+    type Item = ExportRow;
+    const NAME: &'static str = "export";
+    const ITEMS: &'static str = "rows";
+
+    fn write(&mut self, row: ExportRow) -> io::Result<()> {
+        let link = match self.links.entry(row.key.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let dir = self.session_dir.join(&row.key);
+                println!("Exporting {} to `{}`.", row.key, dir.display());
+                e.insert(LinkExport::create(&dir, row.csi.len())?)
+            }
+        };
+
+        if link.csi.columns != Some(row.csi.len()) {
+            link.mismatched += 1;
+            if link.mismatched == 1 {
+                eprintln!("Not exporting rows of width {} for {}, its export has width {}.",
+                          row.csi.len(), row.key, link.csi.columns.unwrap_or(0));
+            }
+            return Ok(())
+        }
+
+        link.append(&row)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        if self.last_sync.elapsed() < HEADER_SYNC_INTERVAL {
+            return Ok(())
+        }
+        self.last_sync = Instant::now();
+        self.sync()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+impl ExportWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(())
+        }
+        for link in self.links.values_mut() {
+            link.sync()?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// starts the export thread if enabled, writing to a new session directory. it finishes
+// the npy headers and exits once the returned Exporter is dropped.
+pub fn start() -> Option<(Exporter, JoinHandle<()>)> {
+    let config = config::get().lock().unwrap().export.clone();
+    if !config.enabled {
+        return None
+    }
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let session_dir = Path::new(&config.dir).join(format!("session-{}", started));
+    if let Err(e) = fs::create_dir_all(&session_dir) {
+        eprintln!("Could not create export directory `{}`: {}", session_dir.display(), e);
+        exit(1);
+    }
+
+    let writer = ExportWriter {
+        session_dir: session_dir.clone(),
+        links: HashMap::new(),
+        dirty: false,
+        last_sync: Instant::now(),
+    };
+
+    let (queue, handle) = writer::start(writer, config.queue_size, HEADER_SYNC_INTERVAL);
+    println!("Exporting CSI matrices to `{}`.", session_dir.display());
+
+    Some((Exporter { queue }, handle))
+}
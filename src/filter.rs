@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use dashmap::DashMap;
+
+use crate::config;
+use crate::mac::MacAddress;
+use crate::record::Record;
+
+// an address block such as 192.168.1.0/24, a bare address matches only itself
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                (u32::from(network) & mask) == (u32::from(ip) & mask)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                (u128::from(network) & mask) == (u128::from(ip) & mask)
+            }
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+
+        let network = IpAddr::from_str(address)
+            .map_err(|_| format!("invalid address `{}`", s))?
+            .to_canonical();
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(p) => p.parse::<u32>()
+                .ok()
+                .filter(|p| *p <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in `{}`", s))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self { network, prefix_len })
+    }
+}
+
+pub struct SourceFilter {
+    allow_addresses: Vec<Cidr>,
+    deny_addresses: Vec<Cidr>,
+    allow_macs: HashSet<MacAddress>,
+    deny_macs: HashSet<MacAddress>,
+
+    // running count of rejected datagrams per source address and frames per sensor mac
+    pub rejected_addresses: DashMap<IpAddr, u64>,
+    pub rejected_macs: DashMap<MacAddress, u64>,
+}
+
+impl SourceFilter {
+    pub fn build(config: &config::Filter) -> Self {
+        Self {
+            allow_addresses: parse_rules(&config.allow_addresses),
+            deny_addresses: parse_rules(&config.deny_addresses),
+            allow_macs: parse_rules(&config.allow_macs).into_iter().collect(),
+            deny_macs: parse_rules(&config.deny_macs).into_iter().collect(),
+            rejected_addresses: DashMap::new(),
+            rejected_macs: DashMap::new(),
+        }
+    }
+
+    // deny rules win over allow rules, an empty allow list allows everything
+    pub fn check_address(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        let allowed = !self.deny_addresses.iter().any(|c| c.contains(&ip))
+            && (self.allow_addresses.is_empty() || self.allow_addresses.iter().any(|c| c.contains(&ip)));
+
+        if !allowed {
+            count_rejection(&self.rejected_addresses, ip, "filtered by config");
+        }
+        allowed
+    }
+
+    pub fn check_mac(&self, mac: &MacAddress) -> bool {
+        let allowed = !self.deny_macs.contains(mac)
+            && (self.allow_macs.is_empty() || self.allow_macs.contains(mac));
+
+        if !allowed {
+            count_rejection(&self.rejected_macs, *mac, "filtered by config");
+        }
+        allowed
+    }
+
+    // sensors.reject_unregistered drops frames from sensors not in the registry, they're
+    // counted and logged along with the filtered ones
+    pub fn reject_unregistered(&self, mac: &MacAddress) {
+        count_rejection(&self.rejected_macs, *mac, "unregistered sensor");
+    }
+
+    // one record per rejecting source so far, tagged with its address or mac
+    pub fn to_records(&self, measurement: &str, timestamp_us: u128) -> Vec<Record> {
+        let addresses = self.rejected_addresses.iter().map(|count| {
+            Record::new(measurement, timestamp_us)
+                .add_field("rejected", *count.value() as i64)
+                .add_tag("address", count.key())
+        });
+        let macs = self.rejected_macs.iter().map(|count| {
+            Record::new(measurement, timestamp_us)
+                .add_field("rejected", *count.value() as i64)
+                .add_tag("mac", count.key().tag())
+        });
+        addresses.chain(macs).collect()
+    }
+}
+
+// only the first rejection per source is logged, so a chatty neighbour can't flood the output
+fn count_rejection<K: Eq + Hash + Display>(counts: &DashMap<K, u64>, key: K, reason: &str) {
+    let mut count = counts.entry(key).or_insert(0);
+    *count += 1;
+    if *count == 1 {
+        println!("Rejecting messages from {} ({}).", count.key(), reason);
+    }
+}
+
+fn parse_rules<T: FromStr>(rules: &[String]) -> Vec<T> where T::Err: Display {
+    rules.iter().map(|r| match r.parse::<T>() {
+        Ok(rule) => rule,
+        Err(e) => {
+            eprintln!("Invalid filter rule in config file: {}", e);
+            exit(1);
+        }
+    }).collect()
+}
+
+pub fn get() -> &'static SourceFilter {
+    static FILTER: OnceLock<SourceFilter> = OnceLock::new();
+    FILTER.get_or_init(|| SourceFilter::build(&config::get().lock().unwrap().filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|r| r.to_string()).collect()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::new(ip(s), 6969)
+    }
+
+    #[test]
+    fn prefixes_mask_the_network() {
+        let block = cidr("192.168.1.77/24");
+        assert!(block.contains(&ip("192.168.1.0")));
+        assert!(block.contains(&ip("192.168.1.255")));
+        assert!(!block.contains(&ip("192.168.2.1")));
+
+        assert!(cidr("10.0.0.0/9").contains(&ip("10.127.255.255")));
+        assert!(!cidr("10.0.0.0/9").contains(&ip("10.128.0.0")));
+    }
+
+    #[test]
+    fn zero_prefixes_match_everything_of_their_family() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(&ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn full_prefixes_and_bare_addresses_match_one_address() {
+        for block in [cidr("192.168.1.5/32"), cidr("192.168.1.5")] {
+            assert!(block.contains(&ip("192.168.1.5")));
+            assert!(!block.contains(&ip("192.168.1.4")));
+        }
+        for block in [cidr("2001:db8::5/128"), cidr("2001:db8::5")] {
+            assert!(block.contains(&ip("2001:db8::5")));
+            assert!(!block.contains(&ip("2001:db8::4")));
+        }
+        assert!(cidr("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_ipv4() {
+        // a dual stack socket reports ipv4 senders like this
+        assert!(cidr("192.168.1.0/24").contains(&ip("::ffff:192.168.1.9")));
+        assert!(cidr("::ffff:192.168.1.0/24").contains(&ip("192.168.1.9")));
+        assert!(!cidr("192.168.1.0/24").contains(&ip("::ffff:192.168.2.9")));
+    }
+
+    #[test]
+    fn invalid_rules_are_errors() {
+        for rule in ["192.168.1.0/33", "2001:db8::/129", "192.168.1.0/", "192.168.1.0/x", "192.168.1/24", "host"] {
+            assert!(rule.parse::<Cidr>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let filter = SourceFilter::build(&config::Filter {
+            allow_addresses: rules(&["192.168.1.0/24"]),
+            deny_addresses: rules(&["192.168.1.13"]),
+            ..Default::default()
+        });
+        assert!(filter.check_address(&addr("192.168.1.12")));
+        assert!(!filter.check_address(&addr("192.168.1.13")));
+        assert!(!filter.check_address(&addr("10.0.0.1")));
+        assert_eq!(*filter.rejected_addresses.get(&ip("192.168.1.13")).unwrap(), 1);
+    }
+
+    #[test]
+    fn empty_allow_lists_allow_everything_not_denied() {
+        let filter = SourceFilter::build(&config::Filter {
+            deny_macs: rules(&["24:0A:C4:00:00:02"]),
+            ..Default::default()
+        });
+        assert!(filter.check_address(&addr("203.0.113.9")));
+        assert!(filter.check_mac(&"24:0A:C4:00:00:01".parse().unwrap()));
+        assert!(!filter.check_mac(&"24:0A:C4:00:00:02".parse().unwrap()));
+        assert!(!filter.check_mac(&"24:0A:C4:00:00:02".parse().unwrap()));
+        assert_eq!(*filter.rejected_macs.get(&"24:0A:C4:00:00:02".parse().unwrap()).unwrap(), 2);
+    }
+}
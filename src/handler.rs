@@ -1,102 +1,282 @@
-use std::ptr::null;
-use influxdb::{WriteQuery, InfluxDbWriteable};
-
-use crate::{config, csi, telemetry};
-use crate::error::RecvMessageError;
+use crate::{config, csi, filter, sensors, telemetry};
+use crate::archive::Archiver;
+use crate::export::Exporter;
+use crate::config::CrcPolicy;
+use crate::error::{CSIReadingError, RecvMessageError};
 use crate::message::{MessageData, MessageType};
 
-use std::sync::Arc;
-
 use dashmap::DashMap;
-use ndarray::{Array, Axis};
-use num::range;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
+use crate::container::CompressedContainer;
 use crate::csi::{CSIReading, CSIStore};
-
-fn csi_metrics_measurement() -> String {
-    config::get().lock().unwrap().influx.csi_metrics_measurement.clone()
+use crate::denoise::FilterChain;
+use crate::mac::MacAddress;
+use crate::record::Record;
+use crate::throwie::CsiMessage;
+use crate::resample::{Resampler, Sample};
+use crate::presence::{PresenceDetector, PresenceState, Transition, ZonePresence};
+use crate::vitals::{VitalSigns, VitalsTracker};
+use crate::window::WindowMetrics;
+
+// state shared between all handler tasks
+#[derive(Default)]
+pub struct HandlerState {
+    // latest reading and window buffer per mac/antenna link
+    pub frame_map: DashMap<String, CSIStore>,
+    // running count of csi_crc32 mismatches per sensor
+    pub crc_mismatches: DashMap<MacAddress, u64>,
+    // raw frames are queued here when archiving is enabled
+    pub archiver: Option<Archiver>,
+    // mapped readings are queued here when exporting is enabled
+    pub exporter: Option<Exporter>,
+    // presence of each zone, from the presence of its links
+    pub zones: DashMap<String, ZonePresence>,
 }
 
-pub fn handle_message(m: MessageData, f: &Arc<DashMap<String, CSIStore>>) -> Result<Vec<WriteQuery>, RecvMessageError> {
+pub fn handle_message(m: MessageData, s: &HandlerState) -> Result<Vec<Record>, RecvMessageError> {
     match m.format {
         MessageType::Telemetry => handle_telemetry(m),
-        MessageType::CSI => handle_csi(m, f),
-        MessageType::CSICompressed => handle_compressed_csi(m, f)
+        MessageType::CSI => handle_csi(m, s),
+        MessageType::CSICompressed => handle_compressed_csi(m, s)
     }
 }
 
-fn handle_telemetry(message: MessageData) -> Result<Vec<WriteQuery>, RecvMessageError> {
+fn handle_telemetry(message: MessageData) -> Result<Vec<Record>, RecvMessageError> {
     let reading = parse_telemetry(&message.payload)?;
-    Ok(vec![reading.into_query(&config::get().lock().unwrap().influx.sensor_telemetry_measurement)])
+    Ok(vec![reading.to_record(&config::get().lock().unwrap().influx.sensor_telemetry_measurement)])
 }
 
 fn parse_telemetry(expected_payload: &[u8]) -> Result<telemetry::TelemetryReading, RecvMessageError> {
     let protobuf_parse_result = telemetry::parse_telemetry_protobuf(expected_payload)?;
-    Ok(telemetry::get_reading(&protobuf_parse_result))
+    let mut reading = telemetry::get_reading(&protobuf_parse_result)?;
+    check_mac(&reading.mac_address)?;
+    reading.set_sensor(sensors::registry().lookup(&reading.mac_address)?);
+    Ok(reading)
 }
 
-fn handle_csi(message: MessageData, f: &Arc<DashMap<String, CSIStore>>) -> Result<Vec<WriteQuery>, RecvMessageError> {
-    let frame = parse_csi(&message.payload)?;
-    let mapped_reading = map_reading(frame, f);
-
-    Ok(vec![mapped_reading.into_query(csi_metrics_measurement())])
+fn handle_csi(message: MessageData, s: &HandlerState) -> Result<Vec<Record>, RecvMessageError> {
+    let frame = parse_csi(&message.payload, &message, s)?;
+    let mut records = Vec::new();
+    process_reading(frame, message.received_us, s, &mut records);
+    Ok(records)
 }
 
-fn parse_csi(expected_payload: &[u8]) -> Result<CSIReading, RecvMessageError>  {
-    let frame = csi::parse_csi_protobuf(&expected_payload)?;
-    csi::get_reading(&frame)
+fn process_reading(reading: CSIReading, received_us: i64, s: &HandlerState, records: &mut Vec<Record>) {
+    let (mapped_reading, metrics) = map_reading(reading, &s.frame_map);
+    if let Some(exporter) = &s.exporter {
+        exporter.record(&mapped_reading);
+    }
+
+    let (csi_metrics, csi_window, vitals, presence) = {
+        let config = &config::get().lock().unwrap().influx;
+        (config.csi_metrics_measurement.clone(), config.csi_window_measurement.clone(),
+         config.vitals_measurement.clone(), config.presence_measurement.clone())
+    };
+    records.push(mapped_reading.to_record(&csi_metrics));
+    if let Some(window) = metrics.window {
+        records.push(window.to_record(&mapped_reading, &csi_window));
+    }
+    if let Some(vital_signs) = metrics.vitals {
+        records.push(vital_signs.to_record(&mapped_reading, &vitals));
+    }
+    if let Some(transition) = metrics.presence {
+        records.push(transition.to_record(&mapped_reading, &presence));
+    }
+    // only sensors given a zone in the config share one, the rest would be pooled
+    // under their placeholder zone
+    let zoned = || sensors::registry().lookup(&mapped_reading.mac_address).is_ok_and(|sensor| sensor.zoned);
+    if let Some(state) = metrics.presence_state.filter(|_| zoned()) {
+        let link = format!("{}/{}", mapped_reading.mac, mapped_reading.antenna);
+        // sensors' clocks don't agree, so zones go by the server's
+        let received_us = u128::try_from(received_us).unwrap_or(0);
+        let zone_transition = s.zones.entry(mapped_reading.zone.clone()).or_insert_with(ZonePresence::new)
+            .update(&mapped_reading.zone, &link, received_us, state);
+        if let Some(zone_transition) = zone_transition {
+            records.push(zone_transition.to_zone_record(&mapped_reading.zone, received_us, &presence));
+        }
+    }
 }
 
-fn handle_compressed_csi(message: MessageData, f: &Arc<DashMap<String, CSIStore>>) -> Result<Vec<WriteQuery>, RecvMessageError> {
-    let mut write_queries: Vec<WriteQuery> = Vec::new();
+fn parse_csi(expected_payload: &[u8], message: &MessageData, s: &HandlerState) -> Result<CSIReading, RecvMessageError>  {
+    let frame = csi::parse_csi_protobuf(expected_payload)?;
+    // the crc is checked before the rest of the frame, so corrupt csi_data is counted
+    // as a mismatch rather than rejected for its length or layout
+    let mac_address = MacAddress::try_from(frame.src_mac.as_slice()).map_err(CSIReadingError::from)?;
+    check_mac(&mac_address)?;
+    // archived as received once the filter lets its sensor through, whether or not it
+    // makes it any further
+    if let Some(archiver) = &s.archiver {
+        archiver.record(&frame, message.addr, message.received_us);
+    }
+    let (crc_valid, crc_mismatches) = check_crc(&frame, &mac_address, s)?;
 
-    let compressed_frame_size = (config::get().lock().unwrap().message.csi_frame_size + 1) as usize;
+    let mut reading = csi::get_reading(&frame)?;
+    reading.set_sensor(sensors::registry().lookup(&reading.mac_address)?);
+    reading.crc_valid = crc_valid;
+    reading.crc_mismatches = crc_mismatches;
+    Ok(reading)
+}
 
-    // batch of readings
-    let expected_compressed_size = u16::from_le_bytes(message.payload[0 .. 2].try_into().unwrap());
-    let expected_end_index = (expected_compressed_size + 2) as usize;
-    //println!("Compressed CSI container with expected_size: {} actual size: {}", expected_compressed_size, message.payload.len() - 2);
+// both errors are already counted by the filter, and only logged there once per sensor
+fn check_mac(mac: &MacAddress) -> Result<(), RecvMessageError> {
+    if !filter::get().check_mac(mac) {
+        return Err(RecvMessageError::SensorFilteredError(*mac))
+    }
+    if let Err(e) = sensors::registry().lookup(mac) {
+        filter::get().reject_unregistered(mac);
+        return Err(e)
+    }
+    Ok(())
+}
+
+// verify csi_crc32 according to the configured policy, keeping a running count of mismatches per sensor.
+// returns whether the crc matched and the sensor's count so far
+fn check_crc(frame: &CsiMessage, mac: &MacAddress, s: &HandlerState) -> Result<(bool, i64), RecvMessageError> {
+    let crc_policy = config::get().lock().unwrap().csi.crc_policy;
+    if crc_policy == CrcPolicy::Accept {
+        return Ok((true, 0))
+    }
 
-    let compressed_payload = message.payload[2 .. expected_end_index].to_vec();
-    let decompressed_data = inflate::inflate_bytes_zlib(compressed_payload.as_slice()).unwrap();
-    let frame_count = decompressed_data.len() / compressed_frame_size;
+    let crc_result = csi::verify_crc(frame);
 
-    if (decompressed_data.len() % compressed_frame_size) > 0 {
-        println!("Could not determine the number of frames in compressed container from {:?} with size: {:?}.", message.addr, decompressed_data.len());
-        return Err(RecvMessageError::MessageDecompressionError())
+    let mut mismatches = s.crc_mismatches.entry(*mac).or_insert(0);
+    if crc_result.is_err() {
+        *mismatches += 1;
     }
+    let count = *mismatches as i64;
 
-    // println!("Frames in container: {:?} from {}", frame_count, message.addr);
+    match crc_result {
+        Err(e) if crc_policy == CrcPolicy::Drop => Err(e.into()),
+        result => Ok((result.is_ok(), count))
+    }
+}
 
-    for i in 0 .. frame_count {
-        let protobuf_size = decompressed_data[compressed_frame_size * i] as usize;
+fn handle_compressed_csi(message: MessageData, s: &HandlerState) -> Result<Vec<Record>, RecvMessageError> {
+    let mut records: Vec<Record> = Vec::new();
 
-        let protobuf_start = (compressed_frame_size * i) + 1;
-        let protobuf_end = protobuf_start + protobuf_size;
-        let protobuf_contents = &decompressed_data[ protobuf_start .. protobuf_end ];
+    let (csi_frame_size, max_decompressed_size) = {
+        let config = &config::get().lock().unwrap().message;
+        (config.csi_frame_size as usize, config.max_decompressed_size)
+    };
 
-        let Ok(msg) = csi::parse_csi_protobuf(protobuf_contents) else {
-            println!("Invalid frame in decompressed array.");
-            continue
+    // batch of readings
+    let container = CompressedContainer::parse(&message.payload, csi_frame_size, max_decompressed_size)?;
+    let mut discarded = container.discarded;
+    let mut filtered = 0;
+    let mut salvaged = 0;
+
+    // println!("Frames in container: {:?} from {}", container.frame_count, message.addr);
+
+    for protobuf_contents in container.frames() {
+        let reading = match parse_csi(protobuf_contents, &message, s) {
+            Ok(r) => r,
+            // already counted by the filter
+            Err(RecvMessageError::SensorFilteredError(_) | RecvMessageError::UnregisteredSensorError(_)) => {
+                filtered += 1;
+                continue
+            }
+            Err(e) => {
+                println!("Invalid frame in decompressed array: {}", e);
+                discarded += 1;
+                continue
+            }
         };
 
-        let Ok(reading) = csi::get_reading(&msg) else {
-            println!("Invalid frame in decompressed array.");
-            continue
-        };
+        process_reading(reading, message.received_us, s, &mut records);
+        salvaged += 1;
+    }
 
-        let mapped_reading = map_reading(reading, f);
-        write_queries.push(mapped_reading.into_query(csi_metrics_measurement()));
+    if salvaged == 0 && discarded > 0 {
+        return Err(RecvMessageError::ContainerDiscardedError(discarded))
+    }
+    if discarded > 0 {
+        println!("Salvaged {} of {} frames in compressed container from {} ({} discarded, {} filtered).",
+                 salvaged, container.frame_count, message.addr, discarded, filtered);
     }
 
-    Ok(write_queries)
+    Ok(records)
+}
+
+// metrics due from a link after one of its readings
+#[derive(Default)]
+struct LinkMetrics {
+    window: Option<WindowMetrics>,
+    vitals: Option<VitalSigns>,
+    presence: Option<Transition>,
+    // the link's presence after this window, for its zone
+    presence_state: Option<PresenceState>,
 }
 
-fn map_reading(mut reading: CSIReading, frame_map: &Arc<DashMap<String, CSIStore>>) -> CSIReading {
+// adds the reading to its link's window and vitals, resampled if enabled, returning
+// any metrics which are due
+fn push_samples(store: &mut CSIStore, reading: &CSIReading, window_ms: u64, hop_ms: u64) -> LinkMetrics {
+    let mut metrics = LinkMetrics::default();
+    let samples = match &mut store.resampler {
+        Some(resampler) => {
+            // windows never span a gap too long to fill
+            if resampler.breaks(reading.timestamp_us, &reading.csi_matrix) {
+                store.buffer.clear();
+                if let Some(vitals) = &mut store.vitals {
+                    vitals.clear();
+                }
+                if let Some(presence) = &mut store.presence {
+                    presence.reset();
+                }
+            }
+            resampler.push(reading.timestamp_us, &reading.csi_matrix)
+        }
+        None => {
+            // a sensor restarting resets its clock, so start the window over
+            if store.buffer.back().is_some_and(|newest| reading.timestamp_us < newest.timestamp_us) {
+                store.buffer.clear();
+                if let Some(presence) = &mut store.presence {
+                    presence.reset();
+                }
+            }
+            vec![Sample { timestamp_us: reading.timestamp_us, csi: reading.csi_matrix.clone(), missing: false }]
+        }
+    };
+    let Some(newest_us) = samples.last().map(|s| s.timestamp_us) else {
+        return metrics
+    };
+    if store.buffer.is_empty() || newest_us < store.last_window_us {
+        store.last_window_us = newest_us;
+    }
+
+    // the ring buffer drops the oldest sample once it holds buffer.window_size
+    for sample in samples {
+        if let Some(vitals) = &mut store.vitals {
+            metrics.vitals = vitals.push(&sample).or(metrics.vitals);
+        }
+        store.buffer.enqueue(sample);
+    }
+
+    let window_us = window_ms as u128 * 1000;
+    while store.buffer.peek().is_some_and(|oldest| newest_us - oldest.timestamp_us > window_us) {
+        store.buffer.dequeue();
+    }
+
+    if hop_ms > 0 && newest_us - store.last_window_us >= hop_ms as u128 * 1000 {
+        store.last_window_us = newest_us;
+        metrics.window = WindowMetrics::compute(&store.buffer);
+    }
+    if let (Some(window), Some(presence)) = (&metrics.window, &mut store.presence) {
+        metrics.presence = presence.update(newest_us, window);
+        metrics.presence_state = Some(presence.state());
+    }
+    metrics
+}
+
+fn map_reading(mut reading: CSIReading, frame_map: &DashMap<String, CSIStore>) -> (CSIReading, LinkMetrics) {
     let sequence_identifier = reading.sequence_identifier;
     let key = format!("{}/{}", reading.mac.clone(), reading.antenna.clone());
 
-    let WINDOW_SIZE: usize = config::get().lock().unwrap().buffer.window_size;
+    let (window_size, window_ms, hop_ms, resample, vitals, presence) = {
+        let config = config::get().lock().unwrap();
+        (config.buffer.window_size, config.buffer.window_ms, config.buffer.hop_ms,
+         config.resample.enabled, config.vitals.enabled, config.presence.enabled)
+    };
+    let mut metrics = LinkMetrics::default();
 
     match frame_map.get_mut(&key) {
         Some(mut stored_frame) => {
@@ -106,68 +286,23 @@ fn map_reading(mut reading: CSIReading, frame_map: &Arc<DashMap<String, CSIStore
             let ret_sequence: i32 = stored_reading.sequence_identifier;
             let new_interval = sequence_identifier - ret_sequence;
 
+            // every reading is filtered, it replaces the stored one either way
+            // and the next pcc is taken against it
+            reading.csi_matrix = stored_frame.filters.apply(&reading.csi_matrix);
+
             // check if this frame arrived out of sequence
             // if so, don't generate metrics as they won't mean anything.
             if sequence_identifier < ret_sequence {
                 reading.interval = ret_sequence;
                 // TODO: Add telemetry message to indicate this occurred.
             } else {
-                // Get PCC
-                let new_matrix = reading.csi_matrix.clone();
-                let corr = csi::get_correlation_coefficient(new_matrix.clone(), &stored_reading.csi_matrix);
+                // pcc against the previous reading, window metrics are written separately
+                let corr = csi::get_correlation_coefficient(&reading.csi_matrix, &stored_frame.reading.csi_matrix);
 
                 reading.correlation_coefficient = corr;
                 reading.interval = new_interval;
 
-                if stored_frame.counter > WINDOW_SIZE {
-                    // reset counter
-                    stored_frame.counter = 0;
-
-                    let first_frame: &CSIReading = stored_frame.buffer.peek().unwrap();
-                    let mut prev_frame: &CSIReading = stored_frame.buffer.peek().unwrap();
-                    let mut prim_vec = Vec::new();
-                    for frame in stored_frame.buffer.iter() {
-                        if frame.timestamp_us < prev_frame.timestamp_us {
-                            // frame received out of order. drop this one.
-                            continue;
-                        } else if (frame.timestamp_us - first_frame.timestamp_us) > 1000000 { // if the window exceeds the time frame (1s in microseconds)
-                            break;
-                        } else {
-                            prim_vec.push(frame.csi_matrix.clone());
-                            prev_frame = frame;
-                        }
-                    }
-
-                    // let mut matrix = Array::zeros((prim_vec.len(), csi::ACTIVE_SUBCARRIERS));
-                    // for (i, frame) in prim_vec.iter().enumerate() {
-                    //     for j in range(0, frame.len()) {
-                    //         matrix[[i, j]] = frame[[0, j]];
-                    //     }
-                    // }
-                    //
-                    // print!("{:?}", matrix.shape());
-                    // print!("first_frame: {} frame: {}\n", first_frame.timestamp_us, stored_frame.buffer.back().unwrap().timestamp_us);
-
-                    // let resampled_sequence = sci_rs::signal::resample::resample(matrix.slice_axis(Axis(0), ), WINDOW_SIZE);
-
-                    // compute metrics. for fun. and profit.
-                    //let corr_window = csi::get_correlation_coefficient(
-                    //    prim_vec.first().unwrap().clone(),
-                    //    &prim_vec.last().unwrap().clone()
-                    //);
-                    let corr_window = csi::get_correlation_coefficient(
-                        stored_frame.buffer.peek().unwrap().csi_matrix.clone(),
-                        &stored_frame.buffer.back().unwrap().csi_matrix.clone()
-                    );
-
-                    reading.correlation_coefficient = corr_window;
-                } else {
-                    // print!("{}\n", stored_frame.buffer.len());
-                    stored_frame.buffer.push(reading.clone());
-                    stored_frame.counter += 1;
-
-                    reading.correlation_coefficient = stored_frame.reading.correlation_coefficient;
-                }
+                metrics = push_samples(&mut stored_frame, &reading, window_ms, hop_ms);
             }
 
             if reading.interval > 65000 {
@@ -175,18 +310,29 @@ fn map_reading(mut reading: CSIReading, frame_map: &Arc<DashMap<String, CSIStore
                 reading.interval = sequence_identifier + ret_diff_from_max;
             }
 
-            // *stored_frame = reading.clone();
             stored_frame.reading = reading.clone();
         }
         None => {
-            frame_map.insert(key.clone(), CSIStore {
-                buffer: AllocRingBuffer::new(WINDOW_SIZE),
+            let mut filters = FilterChain::new();
+            reading.csi_matrix = filters.apply(&reading.csi_matrix);
+            let mut store = CSIStore {
+                filters,
+                buffer: AllocRingBuffer::new(window_size),
+                resampler: resample.then(Resampler::new),
+                vitals: vitals.then(VitalsTracker::new),
+                presence: presence.then(|| {
+                    // the reading was only let through if its sensor is registered or allowed
+                    let overrides = sensors::registry().lookup(&reading.mac_address).map(|s| s.presence).unwrap_or_default();
+                    PresenceDetector::new(&key, overrides)
+                }),
                 reading: reading.clone(),
-                counter: 0
-            });
-            println!("Added new client with key: {} (time: {})", key.clone(), reading.time.clone());
+                last_window_us: reading.timestamp_us
+            };
+            push_samples(&mut store, &reading, window_ms, hop_ms);
+            frame_map.insert(key.clone(), store);
+            println!("Added new client with key: {} (time: {})", key.clone(), reading.timestamp_us);
         }
     }
 
-    reading
+    (reading, metrics)
 }
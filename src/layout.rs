@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::process::exit;
+use std::sync::OnceLock;
+
+use ndarray::{Array, Ix2};
+use num::complex::Complex32;
+
+use crate::config::{self, Chip, IqOrder};
+use crate::error::CSIReadingError;
+use crate::mac::MacAddress;
+
+// subcarriers covered by the invalid first word on the original esp32
+const FIRST_WORD_SUBCARRIERS: usize = 2;
+
+// where the subcarriers of one kind of csi_data buffer are, and which of them carry data
+pub struct Layout {
+    pub name: String,
+    pub data_length: usize,
+    // (subcarrier position in csi_data, frequency index) of each extracted subcarrier
+    subcarriers: Vec<(usize, i32)>,
+}
+
+impl Layout {
+    fn new(name: &str, data_length: usize, field_offset: usize, field_size: usize, indices: &[i32]) -> Result<Self, String> {
+        let half = (field_size / 2) as i32;
+        let mut subcarriers = Vec::with_capacity(indices.len());
+        for &k in indices {
+            if k < -half || k >= half {
+                return Err(format!("subcarrier {} is outside a field of {} subcarriers", k, field_size))
+            }
+            // fields hold subcarriers 0..n/2-1 then -n/2..-1
+            let position = field_offset + if k >= 0 { k as usize } else { (field_size as i32 + k) as usize };
+            if (position + 1) * 2 > data_length {
+                return Err(format!("subcarrier {} is beyond the end of {} bytes of csi_data", k, data_length))
+            }
+            subcarriers.push((position, k));
+        }
+        if subcarriers.is_empty() {
+            return Err(String::from("no subcarriers to extract"))
+        }
+
+        Ok(Self { name: name.to_string(), data_length, subcarriers })
+    }
+}
+
+// data subcarriers min..=max either side of dc
+fn symmetric(min: i32, max: i32) -> Vec<i32> {
+    (min..=max).chain(-max..=-min).collect()
+}
+
+// in the order they're chosen by csi_data length, so 384 bytes is read as ht40 unless
+// a sensor says it sends stbc frames. esp32-s2/s3/c3/c6 report the same fields.
+fn built_in() -> Vec<Layout> {
+    let layouts = [
+        // non-ht frames, legacy long training field only
+        ("lltf", 128, 0, 64, symmetric(1, 26)),
+        // ht20, lltf then ht-ltf
+        ("ht_ltf", 256, 64, 64, symmetric(1, 28)),
+        // ht40, lltf then ht-ltf across both 20 MHz halves
+        ("ht40_ht_ltf", 384, 64, 128, symmetric(2, 58)),
+        // ht20 stbc, lltf, ht-ltf then stbc-ht-ltf
+        ("stbc_ht_ltf", 384, 64, 64, symmetric(1, 28)),
+    ];
+
+    layouts.into_iter()
+        .map(|(name, data_length, offset, size, indices)| {
+            Layout::new(name, data_length, offset, size, &indices).expect("built-in layouts are valid")
+        })
+        .collect()
+}
+
+struct SensorFormat {
+    layout: Option<usize>,
+    iq_order: IqOrder,
+    first_word_invalid: bool,
+}
+
+pub struct Layouts {
+    // configured layouts first, so they take precedence over built-ins of the same length
+    layouts: Vec<Layout>,
+    default: SensorFormat,
+    sensors: HashMap<MacAddress, SensorFormat>,
+}
+
+impl Layouts {
+    pub fn build(csi: &config::Csi, sensors: &config::Sensors) -> Self {
+        let fail = |e: String| -> ! {
+            eprintln!("Invalid subcarrier layout in config file: {}", e);
+            exit(1);
+        };
+
+        let mut names: Vec<&String> = csi.layouts.keys().collect();
+        names.sort();
+        let mut layouts: Vec<Layout> = names.into_iter()
+            .map(|name| {
+                let l = &csi.layouts[name];
+                Layout::new(name, l.data_length, l.field_offset, l.field_size, &l.subcarriers)
+                    .unwrap_or_else(|e| fail(format!("csi.layouts.{}: {}", name, e)))
+            })
+            .collect();
+        layouts.extend(built_in().into_iter().filter(|b| !csi.layouts.contains_key(&b.name)));
+
+        let find = |name: &Option<String>, key: &str| name.as_ref().map(|name| {
+            layouts.iter().position(|l| &l.name == name)
+                .unwrap_or_else(|| fail(format!("{}: no layout named `{}`", key, name)))
+        });
+
+        let default = SensorFormat {
+            layout: find(&csi.layout, "csi.layout"),
+            iq_order: csi.iq_order,
+            first_word_invalid: true,
+        };
+
+        let mut formats = HashMap::new();
+        for (key, alias) in &sensors.devices {
+            // the sensor registry reports invalid macs
+            let Ok(mac) = key.parse::<MacAddress>() else {
+                continue
+            };
+            formats.insert(mac, SensorFormat {
+                layout: find(&alias.layout, &format!("sensors.devices.\"{}\".layout", key)).or(default.layout),
+                iq_order: alias.iq_order.unwrap_or(csi.iq_order),
+                first_word_invalid: alias.chip == Chip::Esp32,
+            });
+        }
+
+        Self { layouts, default, sensors: formats }
+    }
+
+    // the sensor's subcarriers in buffer order, with the frequency index of each column
+    pub fn extract(&self, mac: &MacAddress, csi_data: &[u8]) -> Result<(Array<Complex32, Ix2>, Vec<i32>), CSIReadingError> {
+        let format = self.sensors.get(mac).unwrap_or(&self.default);
+        let layout = match format.layout {
+            Some(i) => &self.layouts[i],
+            None => self.layouts.iter()
+                .find(|l| l.data_length == csi_data.len())
+                .ok_or(CSIReadingError::UnknownCSILayout(csi_data.len()))?,
+        };
+        if csi_data.len() != layout.data_length {
+            return Err(CSIReadingError::InvalidCSILength(csi_data.len(), layout.data_length))
+        }
+
+        let subcarriers: Vec<(usize, i32)> = layout.subcarriers.iter()
+            .copied()
+            .filter(|(position, _)| !format.first_word_invalid || *position >= FIRST_WORD_SUBCARRIERS)
+            .collect();
+
+        let mut csi_complex = Array::zeros((1, subcarriers.len()));
+        for (dest, (src, _)) in subcarriers.iter().enumerate() {
+            let (first, second) = (csi_data[src * 2] as i8 as f32, csi_data[src * 2 + 1] as i8 as f32);
+            csi_complex[[0, dest]] = match format.iq_order {
+                IqOrder::ImagReal => Complex32::new(second, first),
+                IqOrder::RealImag => Complex32::new(first, second),
+            };
+        }
+
+        Ok((csi_complex, subcarriers.into_iter().map(|(_, k)| k).collect()))
+    }
+}
+
+pub fn get() -> &'static Layouts {
+    static LAYOUTS: OnceLock<Layouts> = OnceLock::new();
+    LAYOUTS.get_or_init(|| {
+        let config = config::get().lock().unwrap();
+        Layouts::build(&config.csi, &config.sensors)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSOR: &str = "24:0A:C4:00:00:01";
+    const OTHER: &str = "24:0A:C4:00:00:02";
+
+    // the built-ins, and a sensor set to read 384 bytes as stbc
+    fn layouts(iq_order: IqOrder, first_word_invalid: bool) -> Layouts {
+        let layouts = built_in();
+        let stbc = layouts.iter().position(|l| l.name == "stbc_ht_ltf");
+        let sensors = HashMap::from([
+            (SENSOR.parse().unwrap(), SensorFormat { layout: stbc, iq_order, first_word_invalid }),
+        ]);
+        Layouts { layouts, default: SensorFormat { layout: None, iq_order, first_word_invalid }, sensors }
+    }
+
+    // each subcarrier's first byte is its position in csi_data mod 128, the second the
+    // position / 128, so the position can be read back from either iq order
+    fn csi_data(len: usize) -> Vec<u8> {
+        (0..len / 2).flat_map(|p| [(p % 128) as u8, (p / 128) as u8]).collect()
+    }
+
+    // positions of the extracted subcarriers, with their frequency indices
+    fn extract(layouts: &Layouts, mac: &str, len: usize) -> (Vec<usize>, Vec<i32>) {
+        let (csi, indices) = layouts.extract(&mac.parse().unwrap(), &csi_data(len)).unwrap();
+        let positions = csi.iter().map(|c| (c.re + c.im * 128.0) as usize).collect();
+        (positions, indices)
+    }
+
+    // where a field of size subcarriers starting at offset keeps each index
+    fn positions(offset: usize, size: i32, indices: &[i32]) -> Vec<usize> {
+        indices.iter().map(|&k| offset + if k >= 0 { k } else { size + k } as usize).collect()
+    }
+
+    #[test]
+    fn lltf_is_read_from_128_bytes() {
+        let (found, indices) = extract(&layouts(IqOrder::RealImag, false), OTHER, 128);
+        assert_eq!(indices, symmetric(1, 26));
+        assert_eq!(found, positions(0, 64, &indices));
+    }
+
+    #[test]
+    fn ht_ltf_is_read_from_256_bytes() {
+        let (found, indices) = extract(&layouts(IqOrder::RealImag, false), OTHER, 256);
+        assert_eq!(indices, symmetric(1, 28));
+        assert_eq!(found, positions(64, 64, &indices));
+    }
+
+    #[test]
+    fn ht40_is_read_from_384_bytes_by_default() {
+        let (found, indices) = extract(&layouts(IqOrder::RealImag, false), OTHER, 384);
+        assert_eq!(indices, symmetric(2, 58));
+        assert_eq!(found, positions(64, 128, &indices));
+        assert_eq!(found.len(), 114);
+    }
+
+    #[test]
+    fn sensors_can_send_stbc_in_384_bytes() {
+        let (found, indices) = extract(&layouts(IqOrder::RealImag, false), SENSOR, 384);
+        assert_eq!(indices, symmetric(1, 28));
+        assert_eq!(found, positions(64, 64, &indices));
+    }
+
+    #[test]
+    fn iq_order_picks_the_real_byte() {
+        let mac = OTHER.parse().unwrap();
+        let data = csi_data(256);
+        let (real_imag, _) = layouts(IqOrder::RealImag, false).extract(&mac, &data).unwrap();
+        let (imag_real, _) = layouts(IqOrder::ImagReal, false).extract(&mac, &data).unwrap();
+
+        // subcarrier 1 of the ht-ltf is at position 65
+        assert_eq!(real_imag[[0, 0]], Complex32::new(65.0, 0.0));
+        assert_eq!(imag_real[[0, 0]], Complex32::new(0.0, 65.0));
+    }
+
+    #[test]
+    fn esp32_drops_the_first_word() {
+        let layouts = layouts(IqOrder::RealImag, true);
+        // subcarrier 1 of the lltf is at position 1, within the first four bytes
+        let (found, indices) = extract(&layouts, OTHER, 128);
+        assert_eq!(indices, (2..=26).chain(-26..=-1).collect::<Vec<_>>());
+        assert_eq!(found, positions(0, 64, &indices));
+        // later fields are untouched
+        assert_eq!(extract(&layouts, OTHER, 256).1, symmetric(1, 28));
+    }
+
+    #[test]
+    fn unknown_lengths_are_errors() {
+        let layouts = layouts(IqOrder::RealImag, false);
+        let result = layouts.extract(&OTHER.parse().unwrap(), &csi_data(100));
+        assert!(matches!(result, Err(CSIReadingError::UnknownCSILayout(100))));
+
+        // a sensor set to a layout gets exactly that length
+        let result = layouts.extract(&SENSOR.parse().unwrap(), &csi_data(256));
+        assert!(matches!(result, Err(CSIReadingError::InvalidCSILength(256, 384))));
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config;
+use crate::error::MacAddressError;
+
+pub const MAC_LENGTH: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddress([u8; MAC_LENGTH]);
+
+impl MacAddress {
+    // the truncated format used before MacAddress existed, e.g. 0A:0B:0C:0D:0E:0F -> DEF.
+    // drops leading zeroes and the OUI, so distinct sensors may collide.
+    pub fn legacy(&self) -> String {
+        format!("{:X}{:X}{:X}", self.0[3], self.0[4], self.0[5])
+    }
+
+    // identity used for influx tags and frame_map keys, honouring message.legacy_mac_format
+    pub fn tag(&self) -> String {
+        if config::get().lock().unwrap().message.legacy_mac_format {
+            self.legacy()
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for MacAddress {
+    type Error = MacAddressError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let octets: [u8; MAC_LENGTH] = bytes.try_into()
+            .map_err(|_| MacAddressError::InvalidLength(bytes.len()))?;
+        Ok(Self(octets))
+    }
+}
+
+// accepts AA:BB:CC:DD:EE:FF, AA-BB-CC-DD-EE-FF and AABBCCDDEEFF in either case
+impl FromStr for MacAddress {
+    type Err = MacAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|c| *c != ':' && *c != '-').collect();
+        if digits.len() != MAC_LENGTH * 2 || !digits.is_ascii() {
+            return Err(MacAddressError::ParseError(s.to_string()))
+        }
+
+        let mut octets = [0; MAC_LENGTH];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(&digits[i * 2 .. i * 2 + 2], 16)
+                .map_err(|_| MacAddressError::ParseError(s.to_string()))?;
+        }
+        Ok(Self(octets))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d, e, g)
+    }
+}
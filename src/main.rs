@@ -1,12 +1,32 @@
+use clap::Parser;
+
+use crate::cli::{Cli, Command};
 use crate::error::RecvMessageError;
 
+mod archive;
+mod cli;
+mod container;
 mod csi;
+mod denoise;
 mod config;
-mod db;
 mod error;
+mod export;
+mod filter;
+mod layout;
 mod message;
+mod presence;
+mod record;
+mod replay;
+mod resample;
+mod sensors;
+mod sink;
+mod spool;
 mod telemetry;
 mod handler;
+mod mac;
+mod vitals;
+mod window;
+mod writer;
 
 mod throwie {
     include!(concat!(env!("OUT_DIR"), "/throwie.rs"));
@@ -14,5 +34,28 @@ mod throwie {
 
 #[tokio::main]
 async fn main() -> Result<(), RecvMessageError> {
-    message::get_message().await
-}
\ No newline at end of file
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_path(path);
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => message::get_message().await,
+        Command::CheckConfig => {
+            cli::check_config().await;
+            Ok(())
+        }
+        Command::Decode { datagram, encoding } => {
+            cli::decode(&datagram, encoding);
+            Ok(())
+        }
+        Command::Replay { input, speed, fast, print } => {
+            replay::run(&input, (!fast).then_some(speed), print).await;
+            Ok(())
+        }
+        Command::Version => {
+            println!("throwie-server {}", cli::VERSION);
+            Ok(())
+        }
+    }
+}
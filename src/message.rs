@@ -3,24 +3,27 @@ extern crate num_enum;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use dashmap::DashMap;
-use influxdb::WriteQuery;
 
 use tokio::net::UdpSocket;
-use tokio::sync::{Mutex, watch};
-use tokio::time::sleep;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{interval, sleep};
 
-use crate::{config, handler};
-use crate::db::{DbWatchConfig, InfluxClient, start_batch_watcher};
+use crate::{archive, config, export, filter, handler, layout, sensors, sink};
 use crate::error::RecvMessageError;
+use crate::handler::HandlerState;
+use crate::sink::SinkQueue;
 
 const UDP_MESSAGE_MAX_SIZE: usize = 2000;
 
 #[derive(IntoPrimitive, TryFromPrimitive, Debug, PartialEq)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum MessageType {
     Telemetry = 0x01,
     CSI = 0x02,
@@ -31,9 +34,67 @@ pub enum MessageType {
 pub struct MessageData {
     pub format: MessageType,
     pub addr: SocketAddr,
+    // microseconds since the unix epoch
+    pub received_us: i64,
     pub payload: Vec<u8>
 }
 
+impl MessageData {
+    pub fn from_datagram(datagram: &[u8], addr: SocketAddr) -> Result<Self, RecvMessageError> {
+        let Some((&format_byte, payload)) = datagram.split_first() else {
+            return Err(RecvMessageError::EmptyMessageError(addr))
+        };
+
+        // get packet format from first byte
+        let format = MessageType::try_from(format_byte)
+            .map_err(|_| RecvMessageError::MessageFormatDecodeError(format_byte, addr, datagram.len()))?;
+
+        // rest of buffer = actual payload
+        Ok(Self {
+            format,
+            addr,
+            received_us: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as i64),
+            payload: payload.to_vec()
+        })
+    }
+}
+
+// log a failed message along with its sender, keeping a running count per source address
+fn record_error(error_counts: &DashMap<IpAddr, u64>, addr: SocketAddr, e: &RecvMessageError) {
+    let mut count = error_counts.entry(addr.ip()).or_insert(0);
+    *count += 1;
+    eprintln!("Dropped message from {}: {} ({} errors from this source)", addr, e, *count);
+}
+
+// writes the filter's rejected counts every filter.report_interval_ms, and once more on shutdown
+fn start_rejection_reports(queues: Arc<Vec<SinkQueue>>, mut shutdown: watch::Receiver<bool>) -> Option<JoinHandle<()>> {
+    let (interval_ms, measurement) = {
+        let config = config::get().lock().unwrap();
+        (config.filter.report_interval_ms, config.influx.rejected_measurement.clone())
+    };
+    if interval_ms == 0 {
+        return None
+    }
+
+    Some(tokio::spawn(async move {
+        let mut interval = interval(Duration::from_millis(interval_ms));
+        // the first tick is immediate, there's nothing to report yet
+        interval.tick().await;
+        loop {
+            let stopping = tokio::select! {
+                _ = interval.tick() => false,
+                _ = shutdown.wait_for(|stop| *stop) => true,
+            };
+
+            let now_us = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros());
+            sink::enqueue(&queues, filter::get().to_records(&measurement, now_us)).await;
+            if stopping {
+                break
+            }
+        }
+    }))
+}
+
 fn get_reusable_socket(host: String, port: u16) -> UdpSocket {
     let addr: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
     let udp_sock = socket2::Socket::new(
@@ -54,45 +115,66 @@ fn get_reusable_socket(host: String, port: u16) -> UdpSocket {
     udp_sock.try_into().unwrap()
 }
 
+// resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Could not install SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.expect("Could not install SIGINT handler");
+}
+
 pub async fn get_message() -> Result<(), RecvMessageError> {
     let num_cpus = num_cpus::get();
 
-    let db_tasks = 1;
-    let handler_tasks = num_cpus -1;
+    let handler_tasks = (num_cpus - 1).max(1);
 
-    println!("Running MessageServer with {} db task and {} handler tasks.", db_tasks, handler_tasks);
-    sleep(Duration::from_millis(1000)).await;
+    // the rest of the config is loaded up front, so a mistake in it exits here rather
+    // than in a worker on the first packet
+    sensors::registry();
+    filter::get();
+    layout::get();
 
-    // get batch write threshold from appconfig
-    let batch_size = config::get().lock().unwrap().influx.write_batch_size as usize;
+    println!("Running MessageServer with {} handler tasks.", handler_tasks);
+    sleep(Duration::from_millis(1000)).await;
 
-    // get reusable handles to mutex for db client and temp batch vector, and our frame map
-    let batch: Arc<Mutex<Vec<WriteQuery>>> = Arc::new(Mutex::new(Vec::new()));
-    let db = Arc::new(Mutex::new(InfluxClient::new()));
+    // start a batch watcher per output sink, handlers add records to each sink's queue
+    let (queues, watchers) = sink::start_sinks();
+    let arc_queues = Arc::new(queues);
 
-    // create channel for receiving db write notification
-    let (tx, rx) = watch::channel(false);
-    let arc_tx = Arc::new(tx);
+    // create channel for stopping the workers
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    let arc_frame_map = Arc::new(DashMap::new());
+    // raw frames are archived and csi matrices exported by threads of their own, if enabled
+    let (archiver, archive_thread) = archive::start().unzip();
+    let (exporter, export_thread) = export::start().unzip();
 
-    // start thread to receive/handle db write batch limit notifications
-    start_batch_watcher(DbWatchConfig{
-        tx: arc_tx.clone(),
-        rx,
-        batch: batch.clone(),
-        db: db.clone()
+    let arc_state = Arc::new(HandlerState {
+        archiver,
+        exporter,
+        ..HandlerState::default()
     });
+    let arc_error_counts = Arc::new(DashMap::new());
+    let rejection_reports = start_rejection_reports(arc_queues.clone(), shutdown_rx.clone());
+
+    let mut workers = JoinSet::new();
 
     // num workers = num logical cpus
     for _ in 0..handler_tasks {
-        // get local handles for tx and batch
-        let batch = batch.clone();
-        let tx = arc_tx.clone();
-        let frame_map = arc_frame_map.clone();
+        // get local handles for the sink queues and handler state
+        let queues = arc_queues.clone();
+        let state = arc_state.clone();
+        let error_counts = arc_error_counts.clone();
+        let mut shutdown = shutdown_rx.clone();
 
         // spawn worker thread
-        tokio::spawn(async move {
+        workers.spawn(async move {
             // different UdpSocket instance per worker
             // but the same connection is reused
             let address = String::from(&config::get().lock().unwrap().message.address);
@@ -100,40 +182,71 @@ pub async fn get_message() -> Result<(), RecvMessageError> {
 
             let socket = get_reusable_socket(address, port);
 
-            // continuously read next udp packet
+            // continuously read next udp packet until shutdown.
+            // a message already being handled is always finished first.
             loop {
                 // read incoming udp packet into max size buffer
                 let mut recv_buf = [0; UDP_MESSAGE_MAX_SIZE];
-                let (payload_size, addr) = socket.recv_from(&mut recv_buf)
-                    .await
-                    .expect("Didn't receive data");
-
-                // get packet format from first byte
-                let format = MessageType::try_from(recv_buf[0]).unwrap();
-                // rest of buffer = actual payload
-                let payload = recv_buf[1..payload_size].to_vec();
-
-                let recv_message = MessageData {
-                    format,
-                    addr,
-                    payload
+                let recv_result = tokio::select! {
+                    r = socket.recv_from(&mut recv_buf) => r,
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                };
+                let (payload_size, addr) = match recv_result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("{}", RecvMessageError::from(e));
+                        continue
+                    }
                 };
 
-                // send messagedata to format-specific handler
-                // returns a vector which may contain writequeries to send to db
-                let handled_message = handler::handle_message(recv_message, &frame_map).unwrap();
+                // drop datagrams from filtered addresses before they reach the handlers
+                if !filter::get().check_address(&addr) {
+                    continue
+                }
 
-                // lock the batch so we can add new writequeries
-                // lock lasts until the handle is out of scope
-                let mut local_batch_handle = batch.lock().await;
-                local_batch_handle.extend(handled_message);
+                // send messagedata to format-specific handler
+                // returns a vector which may contain records to send to the sinks
+                // a bad datagram only costs us that datagram, never the worker
+                let handled_message = match MessageData::from_datagram(&recv_buf[..payload_size], addr)
+                    .and_then(|recv_message| handler::handle_message(recv_message, &state)) {
+                    Ok(q) => q,
+                    // already counted by the filter
+                    Err(RecvMessageError::SensorFilteredError(_) | RecvMessageError::UnregisteredSensorError(_)) => continue,
+                    Err(e) => {
+                        record_error(&error_counts, addr, &e);
+                        continue
+                    }
+                };
 
-                // if the batch exceeds write threshold, send db write notification
-                if local_batch_handle.len() > batch_size {
-                    tx.send(true).unwrap();
-                }
+                sink::enqueue(&queues, handled_message).await;
             }
-        }).await.expect("TODO: panic message");
+        });
     }
-    Ok(())
-}
\ No newline at end of file
+
+    shutdown_signal().await;
+    println!("Shutting down, waiting for {} handler tasks.", workers.len());
+    shutdown_tx.send(true).unwrap();
+
+    while let Some(result) = workers.join_next().await {
+        if let Err(e) = result {
+            eprintln!("Handler task failed: {}", e);
+        }
+    }
+
+    if let Some(reports) = rejection_reports {
+        if let Err(e) = reports.await {
+            eprintln!("Rejection report task failed: {}", e);
+        }
+    }
+
+    // the last handle to the archiver and exporter, dropping it lets their threads finish their files
+    drop(arc_state);
+    for thread in archive_thread.into_iter().chain(export_thread) {
+        if thread.join().is_err() {
+            eprintln!("Archive or export thread failed.");
+        }
+    }
+
+    // every worker has stopped, so nothing else can be added to the batches
+    sink::stop_sinks(watchers).await
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::{self, PresenceOverrides, PresenceThresholds};
+use crate::csi::CSIReading;
+use crate::record::Record;
+use crate::window::WindowMetrics;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PresenceState {
+    Empty,
+    Occupied,
+    Motion,
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PresenceState::Empty => "empty",
+            PresenceState::Occupied => "occupied",
+            PresenceState::Motion => "motion",
+        })
+    }
+}
+
+pub struct Transition {
+    pub from: PresenceState,
+    pub to: PresenceState,
+    // how long the link or zone was in the previous state
+    pub held_us: u128,
+}
+
+impl Transition {
+    fn record(&self, measurement: &str, timestamp_us: u128, scope: &str) -> Record {
+        Record::new(measurement, timestamp_us)
+            .add_field("state", self.to.to_string().as_str())
+            .add_field("previous", self.from.to_string().as_str())
+            .add_field("state_code", self.to as i64)
+            .add_field("previous_duration_ms", (self.held_us / 1000) as i64)
+            .add_tag("scope", scope)
+    }
+
+    // tagged like the reading whose window caused it
+    pub fn to_record(&self, reading: &CSIReading, measurement: &str) -> Record {
+        reading.add_tags(self.record(measurement, reading.timestamp_us, "link"))
+    }
+
+    pub fn to_zone_record(&self, zone: &str, timestamp_us: u128, measurement: &str) -> Record {
+        self.record(measurement, timestamp_us, "zone").add_tag("zone", zone)
+    }
+}
+
+// window metrics seen while learning a link's empty baseline
+struct Calibration {
+    until_us: u128,
+    variances: Vec<f32>,
+    pccs: Vec<f32>,
+}
+
+fn mean_and_deviation(values: &[f32]) -> (f32, f32) {
+    let count = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / count;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count;
+    (mean, variance.sqrt())
+}
+
+// the presence state of one link, moved along by its window metrics
+pub struct PresenceDetector {
+    name: String,
+    thresholds: PresenceThresholds,
+    overrides: PresenceOverrides,
+    hold_us: u128,
+    empty_hold_us: u128,
+    state: PresenceState,
+    // when the current state was entered
+    since_us: Option<u128>,
+    // a different state the metrics point to, and since when
+    candidate: Option<(PresenceState, u128)>,
+    calibration: Option<Calibration>,
+    calibrate_us: u128,
+    sigmas: (f32, f32),
+}
+
+impl PresenceDetector {
+    pub fn new(name: &str, overrides: PresenceOverrides) -> Self {
+        let config = config::get().lock().unwrap().presence.clone();
+        Self {
+            name: name.to_string(),
+            thresholds: overrides.apply(config.thresholds),
+            overrides,
+            hold_us: config.hold_ms as u128 * 1000,
+            empty_hold_us: config.empty_hold_ms as u128 * 1000,
+            state: PresenceState::Empty,
+            since_us: None,
+            candidate: None,
+            calibration: None,
+            calibrate_us: config.calibrate_secs as u128 * 1_000_000,
+            sigmas: (config.occupied_sigmas, config.motion_sigmas),
+        }
+    }
+
+    // the state the metrics point to, exits are checked against the current state's thresholds
+    fn level(&self, window: &WindowMetrics) -> PresenceState {
+        let t = &self.thresholds;
+        let variance = window.mean_subcarrier_variance;
+        let motion = if self.state == PresenceState::Motion {
+            variance >= t.motion_exit || window.mean_pcc < t.motion_pcc_exit
+        } else {
+            variance >= t.motion_enter || window.mean_pcc < t.motion_pcc_enter
+        };
+        let occupied = if self.state == PresenceState::Empty {
+            variance >= t.occupied_enter
+        } else {
+            variance >= t.occupied_exit
+        };
+
+        match (motion, occupied) {
+            (true, _) => PresenceState::Motion,
+            (false, true) => PresenceState::Occupied,
+            (false, false) => PresenceState::Empty,
+        }
+    }
+
+    // returns true while still learning the baseline
+    fn calibrate(&mut self, timestamp_us: u128, window: &WindowMetrics) -> bool {
+        if self.calibrate_us == 0 {
+            return false
+        }
+        let calibration = self.calibration.get_or_insert_with(|| Calibration {
+            until_us: timestamp_us + self.calibrate_us,
+            variances: Vec::new(),
+            pccs: Vec::new(),
+        });
+        if timestamp_us < calibration.until_us {
+            calibration.variances.push(window.mean_subcarrier_variance);
+            calibration.pccs.push(window.mean_pcc);
+            return true
+        }
+
+        let (variance, variance_deviation) = mean_and_deviation(&calibration.variances);
+        let (pcc, pcc_deviation) = mean_and_deviation(&calibration.pccs);
+        self.calibrate_us = 0;
+        // without any spread every threshold would sit on the baseline itself
+        if calibration.variances.len() < 2 || variance_deviation == 0.0 {
+            println!("Not enough variation to calibrate presence for {}, keeping the configured thresholds.", self.name);
+            return false
+        }
+        let (occupied, motion) = self.sigmas;
+        let calibrated = PresenceThresholds {
+            occupied_enter: variance + occupied * variance_deviation,
+            occupied_exit: variance + occupied / 2.0 * variance_deviation,
+            motion_enter: variance + motion * variance_deviation,
+            motion_exit: variance + motion / 2.0 * variance_deviation,
+            motion_pcc_enter: pcc - motion * pcc_deviation,
+            motion_pcc_exit: pcc - motion / 2.0 * pcc_deviation,
+        };
+        // thresholds set for the sensor still win
+        self.thresholds = self.overrides.apply(calibrated);
+        println!("Calibrated presence for {} over {} windows: {:?}", self.name, calibration.variances.len(), self.thresholds);
+        false
+    }
+
+    pub fn state(&self) -> PresenceState {
+        self.state
+    }
+
+    // the link's clock went backwards or skipped a gap, so the hold timers and any
+    // calibration still underway start over. the state itself is kept
+    pub fn reset(&mut self) {
+        self.since_us = None;
+        self.candidate = None;
+        if self.calibrate_us != 0 {
+            self.calibration = None;
+        }
+    }
+
+    pub fn update(&mut self, timestamp_us: u128, window: &WindowMetrics) -> Option<Transition> {
+        if self.since_us.is_some_and(|since| timestamp_us < since) {
+            self.reset();
+        }
+        let since_us = *self.since_us.get_or_insert(timestamp_us);
+        if self.calibrate(timestamp_us, window) {
+            return None
+        }
+
+        let level = self.level(window);
+        if level == self.state {
+            self.candidate = None;
+            return None
+        }
+
+        let candidate_since = match self.candidate {
+            Some((candidate, since)) if candidate == level => since,
+            _ => self.candidate.insert((level, timestamp_us)).1,
+        };
+        let hold_us = if level == PresenceState::Empty { self.empty_hold_us } else { self.hold_us };
+        if timestamp_us.saturating_sub(candidate_since) < hold_us {
+            return None
+        }
+
+        let transition = Transition { from: self.state, to: level, held_us: timestamp_us.saturating_sub(since_us) };
+        println!("Presence of {} changed from {} to {}.", self.name, transition.from, transition.to);
+        self.state = level;
+        self.since_us = Some(timestamp_us);
+        self.candidate = None;
+        Some(transition)
+    }
+}
+
+// a zone is in the busiest state of any of its links which are still reporting. its links'
+// sensors each have their own clock, so the zone goes by when their windows were received
+pub struct ZonePresence {
+    // the state of each link, and when it last had a window
+    links: HashMap<String, (PresenceState, u128)>,
+    since_us: Option<u128>,
+    link_timeout_us: u128,
+}
+
+impl ZonePresence {
+    pub fn new() -> Self {
+        let config = config::get().lock().unwrap().presence.clone();
+        Self {
+            links: HashMap::new(),
+            since_us: None,
+            link_timeout_us: config.link_timeout_ms as u128 * 1000,
+        }
+    }
+
+    fn state(&self) -> PresenceState {
+        self.links.values().map(|(state, _)| *state).max().unwrap_or(PresenceState::Empty)
+    }
+
+    // called with a link's state after each of its windows, and when that window's datagram
+    // was received
+    pub fn update(&mut self, zone: &str, link: &str, received_us: u128, state: PresenceState) -> Option<Transition> {
+        let from = self.state();
+        let since_us = *self.since_us.get_or_insert(received_us);
+        self.links.insert(link.to_string(), (state, received_us));
+
+        // handler workers can finish datagrams slightly out of order, hence saturating
+        if self.link_timeout_us > 0 {
+            self.links.retain(|other, (_, seen_us)| {
+                let expired = received_us.saturating_sub(*seen_us) > self.link_timeout_us;
+                if expired {
+                    println!("Presence of {} stopped reporting, dropping it from zone {}.", other, zone);
+                }
+                !expired
+            });
+        }
+
+        let to = self.state();
+        if to == from {
+            return None
+        }
+        println!("Presence of zone {} changed from {} to {}.", zone, from, to);
+        self.since_us = Some(received_us);
+        Some(Transition { from, to, held_us: received_us.saturating_sub(since_us) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u128 = 1_000_000;
+
+    fn detector(calibrate_secs: u128, overrides: PresenceOverrides) -> PresenceDetector {
+        PresenceDetector {
+            name: String::from("test"),
+            thresholds: overrides.apply(PresenceThresholds::default()),
+            overrides,
+            hold_us: 2 * SECOND,
+            empty_hold_us: 5 * SECOND,
+            state: PresenceState::Empty,
+            since_us: None,
+            candidate: None,
+            calibration: None,
+            calibrate_us: calibrate_secs * SECOND,
+            sigmas: (3.0, 10.0),
+        }
+    }
+
+    fn window(variance: f32, pcc: f32) -> WindowMetrics {
+        WindowMetrics {
+            frames: 50,
+            missing: 0,
+            span_us: SECOND,
+            mean_pcc: pcc,
+            mean_subcarrier_variance: variance,
+            max_subcarrier_variance: variance,
+            amplitude_variance: 0.0,
+        }
+    }
+
+    // the transitions from one (variance, pcc) window a second, as (second, from, to, held seconds)
+    fn run(detector: &mut PresenceDetector, start: u128, windows: &[(f32, f32)]) -> Vec<(u128, PresenceState, PresenceState, u128)> {
+        windows.iter().enumerate()
+            .filter_map(|(i, &(variance, pcc))| {
+                let t = start + i as u128;
+                detector.update(t * SECOND, &window(variance, pcc)).map(|tr| (t, tr.from, tr.to, tr.held_us / SECOND))
+            })
+            .collect()
+    }
+
+    const QUIET: f32 = 0.01;
+    const BUSY: f32 = 0.1;
+    const MOVING: f32 = 1.0;
+
+    #[test]
+    fn states_are_held_before_changing() {
+        use PresenceState::*;
+        let mut d = detector(0, PresenceOverrides::default());
+        let mut windows = vec![(QUIET, 1.0); 3];
+        windows.extend([(BUSY, 1.0); 3]);
+        windows.extend([(QUIET, 1.0); 6]);
+        // entering takes hold_ms, going back to empty takes empty_hold_ms
+        assert_eq!(run(&mut d, 0, &windows), [(5, Empty, Occupied, 5), (11, Occupied, Empty, 6)]);
+    }
+
+    #[test]
+    fn exit_thresholds_give_hysteresis() {
+        use PresenceState::*;
+        let mut d = detector(0, PresenceOverrides::default());
+        // between occupied_exit and occupied_enter
+        let between = (0.04, 1.0);
+        let mut windows = vec![between; 3];
+        windows.extend([(BUSY, 1.0); 3]);
+        windows.extend([between; 8]);
+        assert_eq!(run(&mut d, 0, &windows), [(5, Empty, Occupied, 5)]);
+        assert_eq!(d.state(), Occupied);
+    }
+
+    #[test]
+    fn a_new_candidate_restarts_the_hold() {
+        use PresenceState::*;
+        let mut d = detector(0, PresenceOverrides::default());
+        let windows = [(BUSY, 1.0), (MOVING, 1.0), (MOVING, 1.0), (MOVING, 1.0)];
+        assert_eq!(run(&mut d, 0, &windows), [(3, Empty, Motion, 3)]);
+    }
+
+    #[test]
+    fn falling_pcc_alone_is_motion() {
+        use PresenceState::*;
+        let mut d = detector(0, PresenceOverrides::default());
+        assert_eq!(run(&mut d, 0, &[(QUIET, 0.5); 3]), [(2, Empty, Motion, 2)]);
+    }
+
+    #[test]
+    fn time_going_backwards_restarts_the_timers() {
+        use PresenceState::*;
+        let mut d = detector(0, PresenceOverrides::default());
+        assert_eq!(run(&mut d, 100, &[(BUSY, 1.0); 3]), [(102, Empty, Occupied, 2)]);
+
+        // a sensor restart, the hold starts over on the new clock
+        assert_eq!(run(&mut d, 10, &[(MOVING, 1.0); 3]), [(12, Occupied, Motion, 2)]);
+
+        d.reset();
+        assert_eq!(run(&mut d, 0, &[(QUIET, 1.0); 6]), [(5, Motion, Empty, 5)]);
+    }
+
+    #[test]
+    fn calibration_learns_thresholds_from_the_baseline() {
+        let mut d = detector(10, PresenceOverrides { motion_enter: Some(2.0), ..Default::default() });
+        let baseline: Vec<(f32, f32)> = (0..10)
+            .map(|i| if i % 2 == 0 { (0.01, 0.99) } else { (0.03, 0.97) })
+            .collect();
+        assert!(run(&mut d, 0, &baseline).is_empty());
+
+        d.update(10 * SECOND, &window(QUIET, 1.0));
+        let t = d.thresholds;
+        assert!((t.occupied_enter - 0.05).abs() < 1e-6);
+        assert!((t.occupied_exit - 0.035).abs() < 1e-6);
+        assert!((t.motion_pcc_enter - 0.88).abs() < 1e-6);
+        // the sensor's own threshold wins
+        assert_eq!(t.motion_enter, 2.0);
+    }
+
+    #[test]
+    fn calibration_without_variation_keeps_the_thresholds() {
+        let mut d = detector(5, PresenceOverrides::default());
+        run(&mut d, 0, &[(QUIET, 1.0); 6]);
+        assert_eq!(d.thresholds.occupied_enter, PresenceThresholds::default().occupied_enter);
+        assert_eq!(d.calibrate_us, 0);
+    }
+
+    #[test]
+    fn calibration_starts_over_after_a_reset() {
+        let mut d = detector(5, PresenceOverrides::default());
+        run(&mut d, 100, &[(QUIET, 1.0); 3]);
+        d.reset();
+        // the old end of calibration is on the previous clock, and nothing is
+        // reported while calibrating however loud
+        assert!(run(&mut d, 0, &[(MOVING, 1.0); 5]).is_empty());
+        assert_eq!(d.calibration.as_ref().unwrap().variances.len(), 5);
+    }
+
+    fn zone(link_timeout_us: u128) -> ZonePresence {
+        ZonePresence { links: HashMap::new(), since_us: None, link_timeout_us }
+    }
+
+    #[test]
+    fn zones_take_their_busiest_link() {
+        use PresenceState::*;
+        let mut zone = zone(60 * SECOND);
+        assert!(zone.update("z", "a", 0, Empty).is_none());
+        assert!(zone.update("z", "b", 0, Empty).is_none());
+
+        let t = zone.update("z", "a", 3 * SECOND, Motion).unwrap();
+        assert_eq!((t.from, t.to, t.held_us), (Empty, Motion, 3 * SECOND));
+        assert!(zone.update("z", "b", 4 * SECOND, Occupied).is_none());
+
+        let t = zone.update("z", "a", 6 * SECOND, Empty).unwrap();
+        assert_eq!((t.from, t.to, t.held_us), (Motion, Occupied, 3 * SECOND));
+        // datagrams finished out of order don't panic
+        assert!(zone.update("z", "b", SECOND, Occupied).is_none());
+        assert_eq!(zone.update("z", "b", 0, Empty).unwrap().held_us, 0);
+    }
+
+    #[test]
+    fn zones_drop_links_which_stop_reporting() {
+        use PresenceState::*;
+        let mut zone = zone(10 * SECOND);
+        zone.update("z", "a", 0, Motion);
+        zone.update("z", "b", 0, Empty);
+
+        assert!(zone.update("z", "b", 10 * SECOND, Empty).is_none());
+        let t = zone.update("z", "b", 10 * SECOND + 1, Empty).unwrap();
+        assert_eq!((t.from, t.to, t.held_us), (Motion, Empty, 10 * SECOND + 1));
+        assert!(!zone.links.contains_key("a"));
+
+        // a link which comes back counts again
+        assert_eq!(zone.update("z", "a", 11 * SECOND, Occupied).unwrap().to, Occupied);
+        assert!(zone.links.contains_key("b"));
+    }
+
+    #[test]
+    fn links_on_distant_clocks_share_the_zone() {
+        use PresenceState::*;
+        // a's sensor clock is a day ahead of b's. the handler passes when each window's
+        // datagram was received instead, so neither link drops the other
+        let mut zone = zone(2 * SECOND);
+        let mut transitions = Vec::new();
+        for i in 0..20 {
+            let received_us = 1_700_000_000 * SECOND + i * SECOND / 2;
+            let (link, state) = if i % 2 == 0 { ("a", Motion) } else { ("b", Empty) };
+            transitions.extend(zone.update("z", link, received_us, state).map(|t| (i, t.from, t.to)));
+        }
+        assert_eq!(transitions, [(0, Empty, Motion)]);
+        assert_eq!(zone.links.len(), 2);
+    }
+}
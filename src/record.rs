@@ -0,0 +1,93 @@
+// a reading in a form every sink understands: a measurement name, tags, typed
+// fields and a timestamp. sinks decide how (and at what precision) to store it.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub measurement: String,
+    pub timestamp_us: u128,
+    pub tags: Vec<(&'static str, String)>,
+    pub fields: Vec<(&'static str, FieldValue)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+impl Record {
+    pub fn new(measurement: &str, timestamp_us: u128) -> Self {
+        Self {
+            measurement: measurement.to_string(),
+            timestamp_us,
+            tags: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn add_tag(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.tags.push((name, value.to_string()));
+        self
+    }
+
+    // NaN and infinite floats are left out, as influx rejects the whole batch over them
+    pub fn add_field(mut self, name: &'static str, value: impl Into<FieldValue>) -> Self {
+        let value = value.into();
+        if let FieldValue::Float(v) = value {
+            if !v.is_finite() {
+                return self
+            }
+        }
+        self.fields.push((name, value));
+        self
+    }
+}
+
+impl From<f32> for FieldValue {
+    fn from(v: f32) -> Self {
+        FieldValue::Float(f64::from(v))
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(v: f64) -> Self {
+        FieldValue::Float(v)
+    }
+}
+
+impl From<i8> for FieldValue {
+    fn from(v: i8) -> Self {
+        FieldValue::Integer(i64::from(v))
+    }
+}
+
+impl From<i16> for FieldValue {
+    fn from(v: i16) -> Self {
+        FieldValue::Integer(i64::from(v))
+    }
+}
+
+impl From<i32> for FieldValue {
+    fn from(v: i32) -> Self {
+        FieldValue::Integer(i64::from(v))
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(v: i64) -> Self {
+        FieldValue::Integer(v)
+    }
+}
+
+impl From<bool> for FieldValue {
+    fn from(v: bool) -> Self {
+        FieldValue::Boolean(v)
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(v: &str) -> Self {
+        FieldValue::String(v.to_string())
+    }
+}
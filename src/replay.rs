@@ -0,0 +1,392 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+use prost::Message;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{archive, cli, config, export, filter, handler, sink};
+use crate::error::{RecvMessageError, ReplayError};
+use crate::handler::HandlerState;
+use crate::message::{MessageData, MessageType};
+use crate::throwie::ArchiveRecord;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const PCAP_MAGIC_US: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NS: u32 = 0xa1b23c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d0d0a;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+// lengths read from a file are checked against these before anything is allocated for them.
+// archived frames arrived as udp datagrams, and no capture keeps more of a frame than
+// libpcap's largest snapshot length
+const MAX_ARCHIVE_RECORD_LEN: usize = u16::MAX as usize;
+const MAX_PCAP_FRAME_LEN: usize = 262144;
+
+// a datagram as the server originally received it
+pub struct RecordedDatagram {
+    pub received_us: i64,
+    pub addr: SocketAddr,
+    pub datagram: Vec<u8>,
+}
+
+type Datagrams = Box<dyn Iterator<Item = Result<RecordedDatagram, ReplayError>>>;
+
+// protobuf varint length prefix, None at a clean end of file
+fn read_varint(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0; 1];
+        if let Err(e) = reader.read_exact(&mut byte) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof if shift == 0 => Ok(None),
+                _ => Err(e),
+            }
+        }
+
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value))
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "length prefix is too long"))
+}
+
+// reads ArchiveRecords from each archive file in turn, see src/proto/archivemsg.proto
+struct ArchiveReader {
+    files: Vec<PathBuf>,
+    current: Option<(String, BufReader<GzDecoder<File>>)>,
+}
+
+impl ArchiveReader {
+    fn new(mut files: Vec<PathBuf>) -> Self {
+        // popped from the back, oldest first
+        files.reverse();
+        Self { files, current: None }
+    }
+
+    fn next_record(&mut self) -> Result<Option<RecordedDatagram>, ReplayError> {
+        loop {
+            let (name, reader) = match &mut self.current {
+                Some(c) => c,
+                None => {
+                    let Some(path) = self.files.pop() else {
+                        return Ok(None)
+                    };
+                    let name = path.display().to_string();
+                    let file = File::open(&path).map_err(|e| ReplayError::ReadError(name.clone(), e))?;
+                    self.current.insert((name, BufReader::new(GzDecoder::new(file))))
+                }
+            };
+
+            let len = match read_varint(reader) {
+                Ok(Some(len)) => len,
+                Ok(None) => {
+                    self.current = None;
+                    continue
+                }
+                Err(e) => return Err(ReplayError::ReadError(name.clone(), e)),
+            };
+
+            if len > MAX_ARCHIVE_RECORD_LEN as u64 {
+                return Err(ReplayError::RecordLengthError(name.clone(), len, MAX_ARCHIVE_RECORD_LEN))
+            }
+            let mut buffer = vec![0; len as usize];
+            reader.read_exact(&mut buffer).map_err(|e| ReplayError::ReadError(name.clone(), e))?;
+            let record = ArchiveRecord::decode(buffer.as_slice())
+                .map_err(|e| ReplayError::ArchiveDecodeError(name.clone(), e))?;
+
+            // archived frames are replayed as the uncompressed csi datagrams they could have arrived as
+            let mut datagram = vec![u8::from(MessageType::CSI)];
+            datagram.extend(record.csi.encode_to_vec());
+
+            return Ok(Some(RecordedDatagram {
+                received_us: record.received_us,
+                addr: record.source_addr.parse().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0))),
+                datagram,
+            }))
+        }
+    }
+}
+
+impl Iterator for ArchiveReader {
+    type Item = Result<RecordedDatagram, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+// reads udp datagrams sent to `port` from a classic (not pcapng) capture file
+struct PcapReader {
+    name: String,
+    reader: BufReader<File>,
+    big_endian: bool,
+    nanosecond: bool,
+    link_type: u32,
+    port: u16,
+}
+
+impl PcapReader {
+    fn open(path: &Path, port: u16) -> Result<Self, ReplayError> {
+        let name = path.display().to_string();
+        let format_error = |reason: &str| ReplayError::PcapFormatError(name.clone(), reason.to_string());
+
+        let file = File::open(path).map_err(|e| ReplayError::ReadError(name.clone(), e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0; 24];
+        reader.read_exact(&mut header).map_err(|e| ReplayError::ReadError(name.clone(), e))?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (big_endian, nanosecond) = match magic {
+            PCAP_MAGIC_US => (false, false),
+            PCAP_MAGIC_NS => (false, true),
+            m if m == PCAP_MAGIC_US.swap_bytes() => (true, false),
+            m if m == PCAP_MAGIC_NS.swap_bytes() => (true, true),
+            PCAPNG_MAGIC => return Err(format_error("pcapng is not supported, convert it with `editcap -F pcap`")),
+            _ => return Err(format_error("unrecognised magic number")),
+        };
+
+        let mut pcap = Self { name: name.clone(), reader, big_endian, nanosecond, link_type: 0, port };
+        // the upper bits carry FCS information we don't need
+        pcap.link_type = pcap.u32_at(&header, 20) & 0xffff;
+        if ![LINKTYPE_NULL, LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_LINUX_SLL, LINKTYPE_IPV4, LINKTYPE_IPV6, LINKTYPE_LINUX_SLL2]
+            .contains(&pcap.link_type) {
+            return Err(format_error(&format!("unsupported link type {}", pcap.link_type)))
+        }
+
+        Ok(pcap)
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let b = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) }
+    }
+
+    fn next_datagram(&mut self) -> Result<Option<RecordedDatagram>, ReplayError> {
+        loop {
+            let mut header = [0; 16];
+            if let Err(e) = self.reader.read_exact(&mut header) {
+                return match e.kind() {
+                    io::ErrorKind::UnexpectedEof => Ok(None),
+                    _ => Err(ReplayError::ReadError(self.name.clone(), e)),
+                }
+            }
+
+            let seconds = i64::from(self.u32_at(&header, 0));
+            let fraction = i64::from(self.u32_at(&header, 4));
+            let captured_len = self.u32_at(&header, 8) as usize;
+            if captured_len > MAX_PCAP_FRAME_LEN {
+                return Err(ReplayError::RecordLengthError(self.name.clone(), captured_len as u64, MAX_PCAP_FRAME_LEN))
+            }
+
+            let mut frame = vec![0; captured_len];
+            self.reader.read_exact(&mut frame).map_err(|e| ReplayError::ReadError(self.name.clone(), e))?;
+
+            let Some((addr, dst_port, payload)) = ip_packet(self.link_type, &frame).and_then(udp_datagram) else {
+                continue
+            };
+            if dst_port != self.port {
+                continue
+            }
+
+            let micros = if self.nanosecond { fraction / 1000 } else { fraction };
+            return Ok(Some(RecordedDatagram {
+                received_us: seconds * 1_000_000 + micros,
+                addr,
+                datagram: payload.to_vec(),
+            }))
+        }
+    }
+}
+
+impl Iterator for PcapReader {
+    type Item = Result<RecordedDatagram, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().transpose()
+    }
+}
+
+fn be16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(offset)?, *bytes.get(offset + 1)?]))
+}
+
+// strip the link layer header, leaving an ip packet
+fn ip_packet(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    match link_type {
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_ETHERNET => {
+            // skip any 802.1Q / 802.1ad tags
+            let mut offset = 12;
+            let mut ethertype = be16(frame, offset)?;
+            while ethertype == 0x8100 || ethertype == 0x88a8 {
+                offset += 4;
+                ethertype = be16(frame, offset)?;
+            }
+            frame.get(offset + 2..)
+        }
+        LINKTYPE_LINUX_SLL => frame.get(16..),
+        LINKTYPE_LINUX_SLL2 => frame.get(20..),
+        _ => Some(frame),
+    }
+}
+
+// source address, destination port and payload of a udp packet, fragments are skipped
+fn udp_datagram(packet: &[u8]) -> Option<(SocketAddr, u16, &[u8])> {
+    let (source, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let fragmented = be16(packet, 6)? & 0x3fff != 0;
+            if *packet.get(9)? != 17 || fragmented {
+                return None
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            (IpAddr::from(Ipv4Addr::from(source)), packet.get(header_len..)?)
+        }
+        6 => {
+            // extension headers aren't followed, sensors don't send them
+            if *packet.get(6)? != 17 {
+                return None
+            }
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            (IpAddr::from(Ipv6Addr::from(source)), packet.get(40..)?)
+        }
+        _ => return None,
+    };
+
+    let src_port = be16(udp, 0)?;
+    let dst_port = be16(udp, 2)?;
+    let len = usize::from(be16(udp, 4)?);
+    // a truncated capture can't be replayed
+    let payload = udp.get(8..len)?;
+
+    Some((SocketAddr::new(source, src_port), dst_port, payload))
+}
+
+fn open_input(path: &Path) -> Result<Datagrams, ReplayError> {
+    let name = path.display().to_string();
+
+    if path.is_dir() {
+        let files = archive::archive_files(path).map_err(|e| ReplayError::ReadError(name, e))?;
+        return Ok(Box::new(ArchiveReader::new(files.into_iter().map(|(path, _, _)| path).collect())))
+    }
+
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map_err(|e| ReplayError::ReadError(name.clone(), e))?;
+
+    if magic[..2] == GZIP_MAGIC {
+        return Ok(Box::new(ArchiveReader::new(vec![path.to_path_buf()])))
+    }
+    let magic = u32::from_le_bytes(magic);
+    if [PCAP_MAGIC_US, PCAP_MAGIC_NS, PCAPNG_MAGIC].iter().any(|m| magic == *m || magic == m.swap_bytes()) {
+        let port = config::get().lock().unwrap().message.port;
+        return Ok(Box::new(PcapReader::open(path, port)?))
+    }
+    Err(ReplayError::UnknownFormatError(name))
+}
+
+// feed recorded datagrams through the handlers. with a speed they're spaced out as
+// originally received (2.0 = twice as fast), without one they're sent as fast as possible.
+// readings go to the enabled sinks, or stdout as line protocol when printing.
+pub async fn run(input: &Path, speed: Option<f64>, print: bool) {
+    if speed.is_some_and(|s| !(s > 0.0 && s.is_finite())) {
+        eprintln!("Replay speed must be greater than 0.");
+        exit(1);
+    }
+
+    let datagrams = match open_input(input) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    // replayed readings can be exported, but they're already archived
+    let (exporter, export_thread) = export::start().unzip();
+    let state = HandlerState {
+        exporter,
+        ..HandlerState::default()
+    };
+    let sinks = (!print).then(sink::start_sinks);
+
+    let started = Instant::now();
+    let mut first_received_us = None;
+    let (mut replayed, mut readings, mut dropped) = (0, 0, 0);
+    let mut failed = false;
+
+    for datagram in datagrams {
+        let datagram = match datagram {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+                break
+            }
+        };
+
+        if let Some(speed) = speed {
+            let first = *first_received_us.get_or_insert(datagram.received_us);
+            let offset_us = (datagram.received_us - first).max(0) as f64 / speed;
+            sleep_until(started + Duration::from_micros(offset_us as u64)).await;
+        }
+
+        if !filter::get().check_address(&datagram.addr) {
+            continue
+        }
+        replayed += 1;
+
+        let handled_message = MessageData::from_datagram(&datagram.datagram, datagram.addr)
+            .and_then(|mut message| {
+                message.received_us = datagram.received_us;
+                handler::handle_message(message, &state)
+            });
+        let records = match handled_message {
+            Ok(r) => r,
+            // already counted by the filter
+            Err(RecvMessageError::SensorFilteredError(_) | RecvMessageError::UnregisteredSensorError(_)) => continue,
+            Err(e) => {
+                dropped += 1;
+                eprintln!("Dropped message from {}: {}", datagram.addr, e);
+                continue
+            }
+        };
+        readings += records.len();
+
+        match &sinks {
+            Some((queues, _)) => sink::enqueue(queues, records).await,
+            None => cli::print_records(records),
+        }
+    }
+
+    println!("Replayed {} datagrams from `{}` ({} readings, {} dropped).", replayed, input.display(), readings, dropped);
+
+    drop(state);
+    if export_thread.is_some_and(|thread| thread.join().is_err()) {
+        eprintln!("Export thread failed.");
+    }
+
+    if let Some((_, watchers)) = sinks {
+        if let Err(e) = sink::stop_sinks(watchers).await {
+            eprintln!("{}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
@@ -0,0 +1,201 @@
+use ndarray::{Array, Ix2};
+
+use crate::config::{self, GapPolicy};
+
+// one point of a link's csi series, as read or resampled onto the grid
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub timestamp_us: u128,
+    pub csi: Array<f32, Ix2>,
+    // no reading was close enough to fill this point, see GapPolicy::Missing
+    pub missing: bool,
+}
+
+// puts one link's readings onto a uniform grid of resample.rate_hz, aligned to multiples
+// of the period so every link shares the same grid
+pub struct Resampler {
+    period_us: u128,
+    policy: GapPolicy,
+    // gaps longer than this aren't filled, the grid starts over after them. 0 for no limit
+    max_gap_us: u128,
+    previous: Option<Sample>,
+    // the next grid point not yet emitted
+    next_us: u128,
+}
+
+impl Resampler {
+    pub fn new() -> Self {
+        let (config, window_ms) = {
+            let config = config::get().lock().unwrap();
+            let vitals_ms = if config.vitals.enabled { config.vitals.window_secs * 1000 } else { 0 };
+            (config.resample.clone(), config.buffer.window_ms.max(vitals_ms))
+        };
+        // a gap longer than every window would only be filled with samples that are dropped
+        // again, and a clock stepping forwards would fill it until memory runs out
+        let max_gap_ms = match config.max_gap_ms {
+            0 => window_ms,
+            max_gap_ms => max_gap_ms.min(window_ms),
+        };
+        Self {
+            period_us: ((1_000_000.0 / config.rate_hz).round() as u128).max(1),
+            policy: config.gap_policy,
+            max_gap_us: max_gap_ms as u128 * 1000,
+            previous: None,
+            next_us: 0,
+        }
+    }
+
+    // true if a reading at timestamp_us can't be joined to the previous one
+    pub fn breaks(&self, timestamp_us: u128, csi: &Array<f32, Ix2>) -> bool {
+        match &self.previous {
+            Some(previous) => timestamp_us < previous.timestamp_us
+                || timestamp_us - previous.timestamp_us > self.max_gap_us
+                || csi.raw_dim() != previous.csi.raw_dim(),
+            None => false,
+        }
+    }
+
+    // grid points up to and including timestamp_us
+    pub fn push(&mut self, timestamp_us: u128, csi: &Array<f32, Ix2>) -> Vec<Sample> {
+        let current = Sample { timestamp_us, csi: csi.clone(), missing: false };
+        if self.breaks(timestamp_us, csi) {
+            self.previous = None;
+        }
+
+        let mut samples = Vec::new();
+        let Some(previous) = self.previous.take() else {
+            self.next_us = timestamp_us.div_ceil(self.period_us) * self.period_us;
+            if self.next_us == timestamp_us {
+                samples.push(current.clone());
+                self.next_us += self.period_us;
+            }
+            self.previous = Some(current);
+            return samples
+        };
+        // a repeated timestamp adds nothing new
+        if timestamp_us == previous.timestamp_us {
+            self.previous = Some(previous);
+            return samples
+        }
+
+        let span = (timestamp_us - previous.timestamp_us) as f32;
+        while self.next_us <= timestamp_us {
+            let t = self.next_us;
+            let sample = match self.policy {
+                GapPolicy::Hold if t < timestamp_us => Sample { timestamp_us: t, ..previous.clone() },
+                GapPolicy::Hold => Sample { timestamp_us: t, ..current.clone() },
+                GapPolicy::Linear => {
+                    let w = (t - previous.timestamp_us) as f32 / span;
+                    let csi = &previous.csi * (1.0 - w) + &current.csi * w;
+                    Sample { timestamp_us: t, csi, missing: false }
+                }
+                // the nearest reading if it's within half a period, otherwise missing
+                GapPolicy::Missing => {
+                    let (nearest, distance) = if t - previous.timestamp_us <= timestamp_us - t {
+                        (&previous, t - previous.timestamp_us)
+                    } else {
+                        (&current, timestamp_us - t)
+                    };
+                    Sample { timestamp_us: t, csi: nearest.csi.clone(), missing: distance * 2 > self.period_us }
+                }
+            };
+            samples.push(sample);
+            self.next_us += self.period_us;
+        }
+
+        self.previous = Some(current);
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    const SECOND: u128 = 1_000_000;
+
+    // a 100 Hz grid
+    fn resampler(policy: GapPolicy, max_gap_us: u128) -> Resampler {
+        Resampler { period_us: 10_000, policy, max_gap_us, previous: None, next_us: 0 }
+    }
+
+    fn points(samples: &[Sample]) -> Vec<(u128, f32, bool)> {
+        samples.iter().map(|s| (s.timestamp_us, s.csi[[0, 0]], s.missing)).collect()
+    }
+
+    #[test]
+    fn first_reading_only_counts_on_a_grid_point() {
+        let mut r = resampler(GapPolicy::Linear, SECOND);
+        assert!(r.push(5_000, &array![[1.0]]).is_empty());
+
+        let mut r = resampler(GapPolicy::Linear, SECOND);
+        assert_eq!(points(&r.push(10_000, &array![[1.0]])), [(10_000, 1.0, false)]);
+    }
+
+    #[test]
+    fn linear_interpolates_at_grid_points() {
+        let mut r = resampler(GapPolicy::Linear, SECOND);
+        r.push(5_000, &array![[0.0, 100.0]]);
+        let samples = r.push(25_000, &array![[20.0, 0.0]]);
+
+        // a quarter and three quarters of the way along
+        assert_eq!(points(&samples), [(10_000, 5.0, false), (20_000, 15.0, false)]);
+        assert_eq!(samples[0].csi, array![[5.0, 75.0]]);
+        assert_eq!(samples[1].csi, array![[15.0, 25.0]]);
+    }
+
+    #[test]
+    fn hold_keeps_the_previous_reading() {
+        let mut r = resampler(GapPolicy::Hold, SECOND);
+        r.push(5_000, &array![[0.0]]);
+        assert_eq!(points(&r.push(30_000, &array![[20.0]])), [(10_000, 0.0, false), (20_000, 0.0, false), (30_000, 20.0, false)]);
+    }
+
+    #[test]
+    fn missing_marks_points_without_a_reading_nearby() {
+        let mut r = resampler(GapPolicy::Missing, SECOND);
+        assert_eq!(points(&r.push(0, &array![[0.0]])), [(0, 0.0, false)]);
+        assert_eq!(points(&r.push(40_000, &array![[4.0]])), [
+            (10_000, 0.0, true),
+            (20_000, 0.0, true),
+            (30_000, 4.0, true),
+            (40_000, 4.0, false),
+        ]);
+
+        // within half a period of a reading
+        assert_eq!(points(&r.push(45_000, &array![[5.0]])), []);
+        assert_eq!(points(&r.push(56_000, &array![[6.0]])), [(50_000, 5.0, false)]);
+    }
+
+    #[test]
+    fn repeated_timestamps_add_nothing() {
+        let mut r = resampler(GapPolicy::Linear, SECOND);
+        r.push(10_000, &array![[1.0]]);
+        assert!(r.push(10_000, &array![[2.0]]).is_empty());
+        assert_eq!(points(&r.push(20_000, &array![[3.0]])), [(20_000, 3.0, false)]);
+    }
+
+    #[test]
+    fn gaps_restart_the_grid() {
+        let mut r = resampler(GapPolicy::Linear, 50_000);
+        r.push(10_000, &array![[0.0]]);
+        assert!(!r.breaks(60_000, &array![[1.0]]));
+        assert!(r.breaks(60_001, &array![[1.0]]));
+        // nothing is filled in across the gap
+        assert!(r.push(65_000, &array![[1.0]]).is_empty());
+        assert_eq!(points(&r.push(70_000, &array![[2.0]])), [(70_000, 2.0, false)]);
+    }
+
+    #[test]
+    fn going_back_in_time_or_changing_width_breaks() {
+        let mut r = resampler(GapPolicy::Linear, SECOND);
+        assert!(!r.breaks(10_000, &array![[0.0]]));
+        r.push(10_000, &array![[0.0]]);
+        assert!(r.breaks(5_000, &array![[0.0]]));
+        assert!(r.breaks(20_000, &array![[0.0, 1.0]]));
+        assert!(!r.breaks(SECOND, &array![[0.0]]));
+        // a clock stepping forwards
+        assert!(r.breaks(1_700_000_000 * SECOND, &array![[0.0]]));
+    }
+}
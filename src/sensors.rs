@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::process::exit;
+use std::sync::OnceLock;
+
+use crate::config::{self, PresenceOverrides};
+use crate::error::RecvMessageError;
+use crate::mac::MacAddress;
+
+const UNREGISTERED: &str = "unregistered";
+const UNKNOWN: &str = "unknown";
+
+// human-readable identity attached to every reading from a sensor
+#[derive(Clone, Debug)]
+pub struct SensorInfo {
+    pub name: String,
+    pub room: String,
+    pub zone: String,
+    pub role: String,
+    pub presence: PresenceOverrides,
+    // the zone was set in the config rather than filled in, only these sensors share zone presence
+    pub zoned: bool,
+}
+
+impl SensorInfo {
+    pub fn unregistered() -> Self {
+        Self {
+            name: String::from(UNREGISTERED),
+            room: String::from(UNREGISTERED),
+            zone: String::from(UNREGISTERED),
+            role: String::from(UNREGISTERED),
+            presence: PresenceOverrides::default(),
+            zoned: false,
+        }
+    }
+}
+
+pub struct SensorRegistry {
+    devices: HashMap<MacAddress, SensorInfo>,
+    unregistered: SensorInfo,
+    reject_unregistered: bool,
+}
+
+impl SensorRegistry {
+    pub fn build(config: &config::Sensors) -> Self {
+        let mut devices = HashMap::new();
+
+        for (key, alias) in &config.devices {
+            let mac = match key.parse::<MacAddress>() {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("Invalid sensor in config file: {}", e);
+                    exit(1);
+                }
+            };
+
+            let unknown = || String::from(UNKNOWN);
+            devices.insert(mac, SensorInfo {
+                name: alias.name.clone(),
+                room: alias.room.clone().unwrap_or_else(unknown),
+                zone: alias.zone.clone().unwrap_or_else(unknown),
+                role: alias.role.clone().unwrap_or_else(unknown),
+                presence: alias.presence,
+                zoned: alias.zone.is_some(),
+            });
+        }
+
+        Self {
+            devices,
+            unregistered: SensorInfo::unregistered(),
+            reject_unregistered: config.reject_unregistered,
+        }
+    }
+
+    pub fn lookup(&self, mac: &MacAddress) -> Result<&SensorInfo, RecvMessageError> {
+        match self.devices.get(mac) {
+            Some(info) => Ok(info),
+            None if self.reject_unregistered => Err(RecvMessageError::UnregisteredSensorError(*mac)),
+            None => Ok(&self.unregistered),
+        }
+    }
+}
+
+pub fn registry() -> &'static SensorRegistry {
+    static REGISTRY: OnceLock<SensorRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| SensorRegistry::build(&config::get().lock().unwrap().sensors))
+}
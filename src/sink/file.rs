@@ -0,0 +1,70 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::exit;
+
+use serde_json::{json, Map, Value};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::config;
+use crate::error::SinkError;
+use crate::record::{FieldValue, Record};
+use crate::sink::Sink;
+
+// appends records to a file as JSON lines, e.g.
+// {"measurement":"telemetry","timestamp_us":1000000,"tags":{...},"fields":{...}}
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn new() -> Self {
+        let path = config::get().lock().unwrap().file.path.clone();
+
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => Self { file: File::from_std(file) },
+            Err(e) => {
+                eprintln!("Could not open output file `{}`: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+}
+
+fn to_json(record: &Record) -> Value {
+    let tags: Map<String, Value> = record.tags.iter()
+        .map(|(name, value)| (name.to_string(), Value::from(value.as_str())))
+        .collect();
+    let fields: Map<String, Value> = record.fields.iter()
+        .map(|(name, value)| {
+            let value = match value {
+                FieldValue::Float(v) => json!(v),
+                FieldValue::Integer(v) => json!(v),
+                FieldValue::Boolean(v) => json!(v),
+                FieldValue::String(v) => json!(v),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+
+    json!({
+        "measurement": record.measurement,
+        "timestamp_us": record.timestamp_us,
+        "tags": tags,
+        "fields": fields,
+    })
+}
+
+impl Sink for FileSink {
+    async fn write(&mut self, records: Vec<Record>) -> Result<(), SinkError> {
+        // one write per batch rather than per record
+        let mut buffer = Vec::new();
+        for record in &records {
+            writeln!(buffer, "{}", to_json(record))?;
+        }
+
+        self.file.write_all(&buffer).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
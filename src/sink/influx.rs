@@ -0,0 +1,294 @@
+extern crate influxdb;
+
+use influxdb::{Query, Timestamp, WriteQuery};
+use reqwest::{Certificate, RequestBuilder, StatusCode};
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+use std::time::Duration;
+use tokio::time::sleep;
+use crate::config::{self, InfluxApi, Precision};
+use crate::error::{InfluxError, SinkError};
+use crate::record::{FieldValue, Record};
+use crate::sink::Sink;
+use crate::spool::Spool;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// records carry microsecond timestamps, converted here to the configured write precision
+fn timestamp(timestamp_us: u128, precision: Precision) -> Timestamp {
+    match precision {
+        Precision::Ns => Timestamp::Nanoseconds(timestamp_us * 1000),
+        Precision::Us => Timestamp::Microseconds(timestamp_us),
+        Precision::Ms => Timestamp::Milliseconds(timestamp_us / 1000),
+        Precision::S => Timestamp::Seconds(timestamp_us / 1_000_000),
+    }
+}
+
+pub fn to_query(record: &Record, precision: Precision) -> WriteQuery {
+    let mut query = WriteQuery::new(timestamp(record.timestamp_us, precision), record.measurement.as_str());
+    for (name, value) in &record.fields {
+        query = match value {
+            FieldValue::Float(v) => query.add_field(*name, *v),
+            FieldValue::Integer(v) => query.add_field(*name, *v),
+            FieldValue::Boolean(v) => query.add_field(*name, *v),
+            FieldValue::String(v) => query.add_field(*name, v.as_str()),
+        };
+    }
+    for (name, value) in &record.tags {
+        query = query.add_tag(*name, value.as_str());
+    }
+    query
+}
+
+// the v1 write endpoint spells precisions differently to v2
+fn precision_param(api: InfluxApi, precision: Precision) -> &'static str {
+    match (api, precision) {
+        (InfluxApi::V1, Precision::Ns) => "n",
+        (InfluxApi::V1, Precision::Us) => "u",
+        (_, Precision::Ns) => "ns",
+        (_, Precision::Us) => "us",
+        (_, Precision::Ms) => "ms",
+        (_, Precision::S) => "s",
+    }
+}
+
+// failures to reach influx, server errors and rate limiting are worth retrying,
+// anything else (bad auth, malformed lines, missing bucket) would fail again
+fn is_transient(e: &InfluxError) -> bool {
+    match e {
+        InfluxError::ConnectionError(_) => true,
+        InfluxError::ResponseError(status, _) => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+        InfluxError::LineProtocolError(_) => false,
+    }
+}
+
+enum Auth {
+    None,
+    Basic(String, Option<String>),
+    Token(String),
+}
+
+pub struct InfluxClient {
+    client: reqwest::Client,
+    precision: Precision,
+    write_url: String,
+    write_params: Vec<(&'static str, String)>,
+    ping_url: String,
+    auth: Auth,
+    retry_attempts: u32,
+    retry_base_delay: Duration,
+    retry_max_delay: Duration,
+    spool: Option<Spool>,
+}
+
+impl InfluxClient {
+    pub fn new() -> Self{
+        let config = &config::get().lock().unwrap().influx;
+
+        let url = format!("{}://{}:{}",
+          &config.protocol,
+          &config.address,
+          &config.port
+        );
+        let precision = String::from(precision_param(config.api, config.precision));
+
+        // v3 accepts v2 style writes, with the database standing in for the bucket
+        let (write_url, write_params) = match config.api {
+            InfluxApi::V1 => (
+                format!("{}/write", url),
+                vec![("db", config.database.clone()), ("precision", precision)]
+            ),
+            InfluxApi::V2 | InfluxApi::V3 => {
+                let bucket = config.bucket.clone().unwrap_or_else(|| config.database.clone());
+                let mut params = vec![("bucket", bucket), ("precision", precision)];
+                if let Some(org) = &config.org {
+                    params.push(("org", org.clone()));
+                }
+                (format!("{}/api/v2/write", url), params)
+            }
+        };
+
+        // a token takes precedence, v1 servers with auth enabled accept either
+        let auth = match (&config.token, &config.username) {
+            (Some(token), _) => Auth::Token(token.clone()),
+            (None, Some(username)) => Auth::Basic(username.clone(), config.password.clone()),
+            (None, None) => Auth::None,
+        };
+
+        let spool = config.spool_dir.as_ref().map(|dir| {
+            match Spool::open(Path::new(dir), config.spool_max_bytes) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Could not open spool directory `{}`: {}", dir, e);
+                    exit(1);
+                }
+            }
+        });
+
+        Self{
+            client: Self::get_client(config.ca_cert.as_deref()),
+            precision: config.precision,
+            write_url,
+            write_params,
+            ping_url: format!("{}/ping", url),
+            auth,
+            retry_attempts: config.retry_attempts,
+            retry_base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            retry_max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            spool
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth {
+            Auth::None => request,
+            Auth::Basic(username, password) => request.basic_auth(username, password.as_ref()),
+            Auth::Token(token) => request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token)),
+        }
+    }
+
+    // batches which can't be written because influx is unreachable go to the spool (if
+    // enabled), and are replayed in order ahead of the next batch that is written
+    pub async fn write_given_batch(&mut self, given_batch: Vec<Record>) -> Result<(), InfluxError> {
+        let queries: Vec<WriteQuery> = given_batch.iter().map(|r| to_query(r, self.precision)).collect();
+        let lines = queries.build()?.get();
+
+        if let Err(e) = self.replay_spool().await {
+            return self.spool_or_fail(&lines, e);
+        }
+
+        match self.write_with_retry(&lines).await {
+            Err(e) if is_transient(&e) => self.spool_or_fail(&lines, e),
+            result => result,
+        }
+    }
+
+    async fn write_lines(&self, lines: &str) -> Result<(), InfluxError> {
+        let response = self.authorize(self.client.post(&self.write_url))
+            .query(&self.write_params)
+            .body(lines.to_string())
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(())
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(InfluxError::ResponseError(status, body.trim().to_string()))
+    }
+    // exponential backoff, starting at retry_base_delay_ms and capped at retry_max_delay_ms
+    async fn write_with_retry(&self, lines: &str) -> Result<(), InfluxError> {
+        let mut delay = self.retry_base_delay;
+        let mut attempt = 0;
+
+        loop {
+            match self.write_lines(lines).await {
+                Err(e) if is_transient(&e) && attempt < self.retry_attempts => {
+                    attempt += 1;
+                    println!("Write to InfluxDB failed ({}), retrying in {:?} ({}/{}).", e, delay, attempt, self.retry_attempts);
+                    sleep(delay).await;
+                    delay = (delay * 2).min(self.retry_max_delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // write spooled batches oldest first, stopping at the first one influx still can't take
+    async fn replay_spool(&self) -> Result<(), InfluxError> {
+        let Some(spool) = &self.spool else {
+            return Ok(())
+        };
+
+        loop {
+            let (path, lines) = match spool.oldest() {
+                Ok(Some(batch)) => batch,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    eprintln!("Could not read spool: {}", e);
+                    return Ok(())
+                }
+            };
+
+            match self.write_lines(&lines).await {
+                Ok(()) => println!("Replayed spooled batch {}.", path.display()),
+                Err(e) if is_transient(&e) => return Err(e),
+                // influx will never accept this batch, don't let it hold up the rest
+                Err(e) => eprintln!("Discarding spooled batch {}: {}", path.display(), e),
+            }
+
+            if let Err(e) = spool.remove(&path) {
+                eprintln!("Could not remove spooled batch {}: {}", path.display(), e);
+                return Ok(())
+            }
+        }
+    }
+
+    fn spool_or_fail(&mut self, lines: &str, e: InfluxError) -> Result<(), InfluxError> {
+        let Some(spool) = &mut self.spool else {
+            return Err(e)
+        };
+
+        match spool.push(lines) {
+            Ok(()) => {
+                println!("InfluxDB is unavailable ({}), spooled batch to disk.", e);
+                Ok(())
+            }
+            Err(spool_error) => {
+                eprintln!("Could not spool batch: {}", spool_error);
+                Err(e)
+            }
+        }
+    }
+
+    // returns the build type and version reported by the server
+    pub async fn ping(&self) -> Result<(String, String), InfluxError> {
+        let response = self.authorize(self.client.get(&self.ping_url)).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(InfluxError::ResponseError(status, body.trim().to_string()))
+        }
+
+        let header = |name: &str| response.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+        Ok((header("X-Influxdb-Build"), header("X-Influxdb-Version")))
+    }
+
+    pub fn get_client(ca_cert: Option<&str>) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+
+        if let Some(path) = ca_cert {
+            let certificate = fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|pem| Certificate::from_pem(&pem).map_err(|e| e.to_string()));
+            match certificate {
+                Ok(c) => builder = builder.add_root_certificate(c),
+                Err(e) => {
+                    eprintln!("Could not load CA certificate `{}`: {}", path, e);
+                    exit(1);
+                }
+            }
+        }
+
+        match builder.build() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Could not create InfluxDB client: {}", e);
+                exit(1);
+            }
+        }
+    }
+}
+
+impl Sink for InfluxClient {
+    async fn write(&mut self, records: Vec<Record>) -> Result<(), SinkError> {
+        Ok(self.write_given_batch(records).await?)
+    }
+}
@@ -0,0 +1,270 @@
+pub mod file;
+pub mod influx;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+use crate::config;
+use crate::error::{RecvMessageError, SinkError};
+use crate::record::Record;
+use crate::sink::file::FileSink;
+use crate::sink::influx::InfluxClient;
+
+// somewhere readings are written to. every sink has its own batch and watcher task,
+// so a slow or failing sink never holds up the others.
+pub trait Sink: Send + 'static {
+    fn write(&mut self, records: Vec<Record>) -> impl Future<Output = Result<(), SinkError>> + Send;
+}
+
+#[derive(Default)]
+pub struct Batch {
+    records: Vec<Record>,
+    // when the oldest record still in the batch was added
+    opened_at: Option<Instant>,
+}
+
+impl Batch {
+    // returns true if this opened a new batch, so the watcher can start its flush timer
+    pub fn extend(&mut self, records: Vec<Record>) -> bool {
+        if records.is_empty() {
+            return false
+        }
+        self.records.extend(records);
+
+        if self.opened_at.is_some() {
+            return false
+        }
+        self.opened_at = Some(Instant::now());
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    fn take(&mut self) -> Vec<Record> {
+        self.opened_at = None;
+        std::mem::take(&mut self.records)
+    }
+}
+
+// the handler side of a sink, records are added to its batch from here
+pub struct SinkQueue {
+    name: &'static str,
+    batch: Arc<Mutex<Batch>>,
+    tx: Arc<Sender<bool>>,
+    // notified when a record is added to an empty batch
+    opened: Arc<Notify>,
+    batch_size: usize,
+}
+
+impl SinkQueue {
+    async fn extend(&self, records: Vec<Record>) {
+        // lock lasts until the handle is out of scope
+        let mut batch = self.batch.lock().await;
+        if batch.extend(records) {
+            // start the flush timer for this batch
+            self.opened.notify_one();
+        }
+
+        // if the batch exceeds write threshold, send write notification
+        // no receiver means the watcher has stopped and already said why, so nothing will
+        // take this batch. drop it rather than panic the handler or grow without bound
+        if batch.len() > self.batch_size && self.tx.send(true).is_err() {
+            eprintln!("The {} sink has stopped, dropping {} readings.", self.name, batch.len());
+            batch.take();
+        }
+    }
+}
+
+// the watcher side of a sink, used to stop it once the handlers have finished
+pub struct SinkWatcher {
+    name: &'static str,
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Result<(), SinkError>>,
+}
+
+struct WatchConfig<S: Sink> {
+    name: &'static str,
+    sink: S,
+    tx: Arc<Sender<bool>>,
+    rx: Receiver<bool>,
+    batch: Arc<Mutex<Batch>>,
+    opened: Arc<Notify>,
+    flush_interval: Option<Duration>,
+    // fires once all workers have stopped, triggering the final flush
+    stop: oneshot::Receiver<()>,
+}
+
+async fn flush_batch<S: Sink>(config: &mut WatchConfig<S>) -> Result<(), SinkError> {
+    // take the batch and release the lock before writing
+    let batch_copy = {
+        let mut batch = config.batch.lock().await;
+        if batch.is_empty() {
+            return Ok(())
+        }
+        batch.take()
+    };
+
+    config.sink.write(batch_copy).await
+}
+
+// the batch is written once it passes write_batch_size or its oldest record is
+// flush_interval_ms old, whichever comes first.
+// the returned handle resolves to the result of the final flush
+fn start_batch_watcher<S: Sink>(mut config: WatchConfig<S>) -> JoinHandle<Result<(), SinkError>> {
+    tokio::spawn(async move {
+        loop {
+            let deadline = match config.flush_interval {
+                Some(interval) => config.batch.lock().await.opened_at.map(|t| t + interval),
+                None => None,
+            };
+
+            tokio::select! {
+                changed = config.rx.changed() => { // watched channel value changed
+                    if changed.is_err() {
+                        break;
+                    }
+                    if *config.rx.borrow_and_update() { // only run when hitting batch size limit
+                        // reset channel value
+                        config.tx.send(false).unwrap();
+
+                        if let Err(e) = flush_batch(&mut config).await {
+                            println!("Failed to write batch to {} sink: {}", config.name, e);
+                        }
+                    }
+                }
+                // nothing to time yet, wait for the next batch to open
+                _ = config.opened.notified(), if config.flush_interval.is_some() && deadline.is_none() => {}
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let Err(e) = flush_batch(&mut config).await {
+                        println!("Failed to write batch to {} sink: {}", config.name, e);
+                    }
+                }
+                _ = &mut config.stop => break,
+            }
+        }
+
+        let remaining = config.batch.lock().await.len();
+        println!("Flushing {} remaining readings to {} sink before shutdown.", remaining, config.name);
+        flush_batch(&mut config).await
+    })
+}
+
+fn start_sink<S: Sink>(name: &'static str, sink: S, batch_size: usize, flush_interval_ms: u64) -> (SinkQueue, SinkWatcher) {
+    let (tx, rx) = watch::channel(false);
+    let tx = Arc::new(tx);
+    let batch = Arc::new(Mutex::new(Batch::default()));
+    let opened = Arc::new(Notify::new());
+    let (stop_tx, stop_rx) = oneshot::channel();
+
+    let handle = start_batch_watcher(WatchConfig {
+        name,
+        sink,
+        tx: tx.clone(),
+        rx,
+        batch: batch.clone(),
+        opened: opened.clone(),
+        flush_interval: (flush_interval_ms > 0).then(|| Duration::from_millis(flush_interval_ms)),
+        stop: stop_rx,
+    });
+
+    let queue = SinkQueue { name, batch, tx, opened, batch_size };
+    let watcher = SinkWatcher { name, stop: stop_tx, handle };
+    (queue, watcher)
+}
+
+// start a batch watcher for every enabled sink
+pub fn start_sinks() -> (Vec<SinkQueue>, Vec<SinkWatcher>) {
+    let (influx, file) = {
+        let config = config::get().lock().unwrap();
+        (config.influx.clone(), config.file.clone())
+    };
+
+    let mut sinks = Vec::new();
+    if influx.enabled {
+        sinks.push(start_sink("influx", InfluxClient::new(), influx.write_batch_size as usize, influx.flush_interval_ms));
+    }
+    if file.enabled {
+        sinks.push(start_sink("file", FileSink::new(), file.write_batch_size, file.flush_interval_ms));
+    }
+
+    let names: Vec<&str> = sinks.iter().map(|(q, _)| q.name).collect();
+    println!("Writing readings to: {}.", names.join(", "));
+
+    sinks.into_iter().unzip()
+}
+
+// fan records out to every sink
+pub async fn enqueue(queues: &[SinkQueue], records: Vec<Record>) {
+    if records.is_empty() {
+        return
+    }
+
+    let Some((last, rest)) = queues.split_last() else {
+        return
+    };
+    for queue in rest {
+        queue.extend(records.clone()).await;
+    }
+    last.extend(records).await;
+}
+
+// flush and stop every sink, returning the first failed final flush
+pub async fn stop_sinks(watchers: Vec<SinkWatcher>) -> Result<(), RecvMessageError> {
+    let mut result = Ok(());
+
+    for watcher in watchers {
+        // the watcher may have already stopped, in which case its handle tells us why
+        let _ = watcher.stop.send(());
+        // a watcher which panicked never got to its final flush
+        let flush_result = watcher.handle.await.unwrap_or_else(|e| Err(e.into()));
+
+        match flush_result {
+            Err(e) if result.is_ok() => result = Err(RecvMessageError::SinkWriteError(watcher.name, e)),
+            Err(e) => eprintln!("Failed to write batch to {} sink: {}", watcher.name, e),
+            Ok(()) => {}
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(n: usize) -> Vec<Record> {
+        (0..n).map(|i| Record::new("test", i as u128)).collect()
+    }
+
+    #[tokio::test]
+    async fn a_stopped_watcher_drops_full_batches() {
+        let (tx, rx) = watch::channel(false);
+        let queue = SinkQueue {
+            name: "test",
+            batch: Arc::new(Mutex::new(Batch::default())),
+            tx: Arc::new(tx),
+            opened: Arc::new(Notify::new()),
+            batch_size: 4,
+        };
+
+        queue.extend(records(5)).await;
+        assert_eq!(queue.batch.lock().await.len(), 5);
+        assert!(rx.has_changed().unwrap());
+
+        // the watcher's end goes with it
+        drop(rx);
+        queue.extend(records(3)).await;
+        assert_eq!(queue.batch.lock().await.len(), 0);
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SPOOL_EXTENSION: &str = "lp";
+
+// write-ahead log of line protocol batches which couldn't be written to influx.
+// each batch is one file named by a zero-padded sequence number, so sorting the
+// file names gives the order they were spooled in.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    next_sequence: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut spool = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            next_sequence: 0,
+        };
+        spool.next_sequence = match spool.files()?.last() {
+            Some((path, _)) => sequence_of(path).map_or(0, |s| s + 1),
+            None => 0,
+        };
+
+        Ok(spool)
+    }
+
+    // spooled batches and their sizes, oldest first
+    fn files(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == SPOOL_EXTENSION) && sequence_of(&path).is_some() {
+                files.push((path, entry.metadata()?.len()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    pub fn push(&mut self, lines: &str) -> io::Result<()> {
+        let path = self.dir.join(format!("{:020}.{}", self.next_sequence, SPOOL_EXTENSION));
+        self.next_sequence += 1;
+
+        // write then rename, so a crash never leaves a partial batch behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, lines)?;
+        fs::rename(&tmp_path, &path)?;
+
+        self.evict()
+    }
+
+    // drop the oldest batches until the spool fits in max_bytes
+    fn evict(&self) -> io::Result<()> {
+        let files = self.files()?;
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum();
+
+        for (path, size) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            println!("Spool exceeds {} bytes, discarding oldest batch {}.", self.max_bytes, path.display());
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    pub fn oldest(&self) -> io::Result<Option<(PathBuf, String)>> {
+        match self.files()?.into_iter().next() {
+            Some((path, _)) => {
+                let lines = fs::read_to_string(&path)?;
+                Ok(Some((path, lines)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+fn sequence_of(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
@@ -1,56 +1,94 @@
-use influxdb::Timestamp;
-use influxdb::InfluxDbWriteable;
 use prost::{DecodeError, Message};
 
+use crate::error::{RecvMessageError, TelemetryReadingError};
+use crate::mac::MacAddress;
+use crate::record::Record;
+use crate::sensors::SensorInfo;
 use crate::throwie::TelemetryMessage;
 
-#[derive(InfluxDbWriteable)]
 pub struct TelemetryReading {
-    time: Timestamp,
+    timestamp_us: u128,
     current_sequence_identifier: i16,
     uptime_ms: i64,
 
-    #[influxdb(tag)] device_mac: String,
-    #[influxdb(tag)] version: String,
-    #[influxdb(tag)] device_type: i8,
-    #[influxdb(tag)] message_type: i8,
-    #[influxdb(tag)] is_eth: bool,
+    device_mac: String,
+    sensor_name: String,
+    room: String,
+    zone: String,
+    role: String,
+    version: String,
+    device_type: i8,
+    message_type: i8,
+    is_eth: bool,
+
+    pub mac_address: MacAddress,
 }
 
-impl TelemetryReading {
-    pub fn new(msg: &TelemetryMessage) -> Self{
-        let timestamp_us = u128::try_from(msg.timestamp).unwrap();
-        let time = Timestamp::Microseconds(timestamp_us).into();
+impl TryFrom<&TelemetryMessage> for TelemetryReading {
+    type Error = TelemetryReadingError;
+
+    fn try_from(msg: &TelemetryMessage) -> Result<Self, Self::Error> {
+        let timestamp_us = u128::try_from(msg.timestamp)
+            .map_err(|_| TelemetryReadingError::NegativeTimestamp(msg.timestamp))?;
 
         let message_type = msg.message_type as i8;
         let current_sequence_identifier = msg.current_sequence_identifier as i16;
         let uptime_ms = msg.uptime_ms;
 
-        let device_mac = format!("{:X}{:X}{:X}", msg.device_mac.clone()[3], msg.device_mac.clone()[4], msg.device_mac.clone()[5]);
+        let mac_address = MacAddress::try_from(msg.device_mac.as_slice())?;
+        let device_mac = mac_address.tag();
+        // filled in from the sensor registry by the handler
+        let unregistered = SensorInfo::unregistered();
         let version = msg.version.clone();
         let device_type = msg.device_type as i8;
         let is_eth = msg.is_eth;
 
-        Self {
-            time,
+        Ok(Self {
+            timestamp_us,
             message_type,
             current_sequence_identifier,
             uptime_ms,
             device_mac,
+            mac_address,
+            sensor_name: unregistered.name,
+            room: unregistered.room,
+            zone: unregistered.zone,
+            role: unregistered.role,
             version,
             device_type,
             is_eth,
-        }
+        })
     }
 }
 
-pub fn parse_telemetry_protobuf(expected_protobuf: &[u8]) -> Result<TelemetryMessage, DecodeError> {
-    match TelemetryMessage::decode(expected_protobuf) {
-        Ok(t) => Ok(t),
-        Err(e) => Err(e),
+impl TelemetryReading {
+    pub fn to_record(&self, measurement: &str) -> Record {
+        Record::new(measurement, self.timestamp_us)
+            .add_field("current_sequence_identifier", self.current_sequence_identifier)
+            .add_field("uptime_ms", self.uptime_ms)
+            .add_tag("device_mac", &self.device_mac)
+            .add_tag("sensor_name", &self.sensor_name)
+            .add_tag("room", &self.room)
+            .add_tag("zone", &self.zone)
+            .add_tag("role", &self.role)
+            .add_tag("version", &self.version)
+            .add_tag("device_type", self.device_type)
+            .add_tag("message_type", self.message_type)
+            .add_tag("is_eth", self.is_eth)
     }
+
+    pub fn set_sensor(&mut self, sensor: &SensorInfo) {
+        self.sensor_name = sensor.name.clone();
+        self.room = sensor.room.clone();
+        self.zone = sensor.zone.clone();
+        self.role = sensor.role.clone();
+    }
+}
+
+pub fn parse_telemetry_protobuf(expected_protobuf: &[u8]) -> Result<TelemetryMessage, DecodeError> {
+    TelemetryMessage::decode(expected_protobuf)
 }
 
-pub fn get_reading(msg: &TelemetryMessage) -> TelemetryReading {
-    TelemetryReading::new(msg)
+pub fn get_reading(msg: &TelemetryMessage) -> Result<TelemetryReading, RecvMessageError> {
+    Ok(TelemetryReading::try_from(msg)?)
 }
\ No newline at end of file
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use sci_rs::signal::filter::design::{butter_dyn, DigitalFilter, FilterBandType, FilterOutputType, Sos};
+use sci_rs::signal::filter::sosfiltfilt_dyn;
+
+use crate::config;
+use crate::csi::CSIReading;
+use crate::record::Record;
+use crate::resample::Sample;
+
+// power iterations used to find the first principal component
+const PCA_ITERATIONS: usize = 50;
+// spectra are zero padded to this many times the window for finer peak frequencies
+const FFT_PADDING: usize = 4;
+
+// a rate in one pass band
+pub struct Estimate {
+    pub per_minute: f64,
+    // share of the band's power within two frequency bins of the peak, from 0 to 1
+    pub confidence: f64,
+}
+
+pub struct VitalSigns {
+    pub breathing: Estimate,
+    pub heart_rate: Estimate,
+}
+
+impl VitalSigns {
+    // tagged like the reading which completed the window
+    pub fn to_record(&self, reading: &CSIReading, measurement: &str) -> Record {
+        let record = Record::new(measurement, reading.timestamp_us)
+            .add_field("breathing_rate_bpm", self.breathing.per_minute)
+            .add_field("breathing_confidence", self.breathing.confidence)
+            .add_field("heart_rate_bpm", self.heart_rate.per_minute)
+            .add_field("heart_rate_confidence", self.heart_rate.confidence);
+        reading.add_tags(record)
+    }
+}
+
+struct Band {
+    low_hz: f64,
+    high_hz: f64,
+    sos: Vec<Sos<f64>>,
+}
+
+impl Band {
+    fn new([low_hz, high_hz]: [f64; 2], order: usize, rate_hz: f64) -> Self {
+        let filter = butter_dyn(order, vec![low_hz, high_hz], Some(FilterBandType::Bandpass), Some(false),
+                                Some(FilterOutputType::Sos), Some(rate_hz));
+        let DigitalFilter::Sos(filter) = filter else {
+            unreachable!("butter_dyn returns the requested output type")
+        };
+        Self { low_hz, high_hz, sos: filter.sos }
+    }
+}
+
+// the first principal component of the subcarriers over time, by power iteration
+fn first_component(rows: &[Vec<f64>]) -> Vec<f64> {
+    let width = rows[0].len();
+    let count = rows.len() as f64;
+    let means: Vec<f64> = (0..width).map(|j| rows.iter().map(|r| r[j]).sum::<f64>() / count).collect();
+    let centred: Vec<Vec<f64>> = rows.iter()
+        .map(|r| r.iter().zip(&means).map(|(v, m)| v - m).collect())
+        .collect();
+
+    let mut covariance = vec![vec![0_f64; width]; width];
+    for row in &centred {
+        for (i, a) in row.iter().enumerate() {
+            for (j, b) in row.iter().enumerate() {
+                covariance[i][j] += a * b;
+            }
+        }
+    }
+
+    let mut component = vec![1.0 / (width as f64).sqrt(); width];
+    for _ in 0..PCA_ITERATIONS {
+        let next: Vec<f64> = covariance.iter()
+            .map(|row| row.iter().zip(&component).map(|(c, v)| c * v).sum())
+            .collect();
+        let norm = next.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            break
+        }
+        component = next.into_iter().map(|v| v / norm).collect();
+    }
+
+    centred.iter()
+        .map(|row| row.iter().zip(&component).map(|(v, c)| v * c).sum())
+        .collect()
+}
+
+// strongest frequency of the band-passed signal within the band
+fn estimate(signal: &[f64], band: &Band, rate_hz: f64, planner: &mut FftPlanner<f64>) -> Estimate {
+    let filtered = sosfiltfilt_dyn(signal.iter(), &band.sos);
+
+    let n = (filtered.len() * FFT_PADDING).next_power_of_two();
+    let last = (filtered.len() - 1).max(1) as f64;
+    let mut spectrum: Vec<Complex<f64>> = filtered.iter().enumerate()
+        .map(|(i, v)| {
+            // hann window, to keep leakage from neighbouring frequencies down
+            let w = 0.5 - 0.5 * (2.0 * PI * i as f64 / last).cos();
+            Complex::new(v * w, 0.0)
+        })
+        .collect();
+    spectrum.resize(n, Complex::new(0.0, 0.0));
+    planner.plan_fft_forward(n).process(&mut spectrum);
+
+    let bin_hz = rate_hz / n as f64;
+    let low = (band.low_hz / bin_hz).ceil() as usize;
+    let high = ((band.high_hz / bin_hz).floor() as usize).min(n / 2);
+    let power: Vec<f64> = spectrum[low..=high].iter().map(|c| c.norm_sqr()).collect();
+    let total: f64 = power.iter().sum();
+    let Some((peak, _)) = power.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)) else {
+        return Estimate { per_minute: 0.0, confidence: 0.0 }
+    };
+
+    // the hann main lobe, two of the window's own bins either side of the peak
+    let spread = 2 * n / filtered.len();
+    let near: f64 = power[peak.saturating_sub(spread)..(peak + spread + 1).min(power.len())].iter().sum();
+    Estimate {
+        per_minute: (low + peak) as f64 * bin_hz * 60.0,
+        confidence: if total > 0.0 { near / total } else { 0.0 },
+    }
+}
+
+// breathing and heart rate of one link, from its last vitals.window_secs of resampled csi
+pub struct VitalsTracker {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+    hop_us: u128,
+    last_us: Option<u128>,
+    rate_hz: f64,
+    breathing: Band,
+    heart_rate: Band,
+    planner: FftPlanner<f64>,
+}
+
+impl VitalsTracker {
+    pub fn new() -> Self {
+        let (config, rate_hz) = {
+            let config = config::get().lock().unwrap();
+            (config.vitals.clone(), config.resample.rate_hz)
+        };
+
+        Self {
+            samples: VecDeque::new(),
+            capacity: (config.window_secs as f64 * rate_hz).round() as usize,
+            hop_us: config.hop_ms as u128 * 1000,
+            last_us: None,
+            rate_hz,
+            breathing: Band::new(config.breathing_hz, config.filter_order, rate_hz),
+            heart_rate: Band::new(config.heart_rate_hz, config.filter_order, rate_hz),
+            planner: FftPlanner::new(),
+        }
+    }
+
+    // after a gap the window has to fill again before the next estimate, and the hop
+    // starts over in case the clock went backwards
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.last_us = None;
+    }
+
+    // returns an estimate when the window is full and a hop is due
+    pub fn push(&mut self, sample: &Sample) -> Option<VitalSigns> {
+        if self.samples.back().is_some_and(|last| last.csi.raw_dim() != sample.csi.raw_dim()) {
+            self.samples.clear();
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample.clone());
+
+        if self.samples.len() < self.capacity {
+            return None
+        }
+        if self.last_us.is_some_and(|last| sample.timestamp_us.saturating_sub(last) < self.hop_us) {
+            return None
+        }
+        self.last_us = Some(sample.timestamp_us);
+
+        // missing points hold the reading before them, the grid has to stay uniform
+        let mut rows: Vec<Vec<f64>> = Vec::with_capacity(self.samples.len());
+        for sample in &self.samples {
+            let row = match rows.last() {
+                Some(previous) if sample.missing => previous.clone(),
+                _ => sample.csi.iter().map(|&v| v as f64).collect(),
+            };
+            rows.push(row);
+        }
+
+        let component = first_component(&rows);
+        Some(VitalSigns {
+            breathing: estimate(&component, &self.breathing, self.rate_hz, &mut self.planner),
+            heart_rate: estimate(&component, &self.heart_rate, self.rate_hz, &mut self.planner),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array;
+
+    const RATE_HZ: f64 = 20.0;
+    const BREATHING_HZ: [f64; 2] = [0.1, 0.5];
+    const HEART_RATE_HZ: [f64; 2] = [0.8, 2.0];
+
+    fn sine(hz: f64, amplitude: f64, secs: f64) -> Vec<f64> {
+        let n = (secs * RATE_HZ) as usize;
+        (0..n).map(|i| amplitude * (2.0 * PI * hz * i as f64 / RATE_HZ).sin()).collect()
+    }
+
+    fn rate(signal: &[f64], band: [f64; 2]) -> Estimate {
+        estimate(signal, &Band::new(band, 2, RATE_HZ), RATE_HZ, &mut FftPlanner::new())
+    }
+
+    fn tracker(window_secs: f64, hop_ms: u128) -> VitalsTracker {
+        VitalsTracker {
+            samples: VecDeque::new(),
+            capacity: (window_secs * RATE_HZ) as usize,
+            hop_us: hop_ms * 1000,
+            last_us: None,
+            rate_hz: RATE_HZ,
+            breathing: Band::new(BREATHING_HZ, 2, RATE_HZ),
+            heart_rate: Band::new(HEART_RATE_HZ, 2, RATE_HZ),
+            planner: FftPlanner::new(),
+        }
+    }
+
+    fn sample(i: usize, values: &[f32]) -> Sample {
+        Sample {
+            timestamp_us: i as u128 * 50_000,
+            csi: Array::from_shape_vec((1, values.len()), values.to_vec()).unwrap(),
+            missing: false,
+        }
+    }
+
+    #[test]
+    fn finds_a_breathing_rate() {
+        let estimate = rate(&sine(0.25, 1.0, 30.0), BREATHING_HZ);
+        assert!((estimate.per_minute - 15.0).abs() < 0.5, "{}", estimate.per_minute);
+        assert!(estimate.confidence > 0.9, "{}", estimate.confidence);
+    }
+
+    #[test]
+    fn finds_a_heart_rate() {
+        let estimate = rate(&sine(1.2, 1.0, 30.0), HEART_RATE_HZ);
+        assert!((estimate.per_minute - 72.0).abs() < 0.5, "{}", estimate.per_minute);
+    }
+
+    #[test]
+    fn each_band_picks_its_own_peak() {
+        // breathing dominates, as it does in practice
+        let signal: Vec<f64> = sine(0.3, 1.0, 30.0).iter().zip(sine(1.5, 0.2, 30.0))
+            .map(|(a, b)| a + b)
+            .collect();
+        assert!((rate(&signal, BREATHING_HZ).per_minute - 18.0).abs() < 0.5);
+        assert!((rate(&signal, HEART_RATE_HZ).per_minute - 90.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn still_links_have_no_confidence() {
+        // first_component centres the subcarriers, so a link without variation is all zeros
+        assert_eq!(rate(&[0.0; 600], BREATHING_HZ).confidence, 0.0);
+    }
+
+    #[test]
+    fn first_component_recovers_a_shared_signal() {
+        let signal = sine(0.25, 1.0, 10.0);
+        let weights = [2.0, -1.0, 0.5];
+        let rows: Vec<Vec<f64>> = signal.iter()
+            .map(|s| weights.iter().enumerate().map(|(j, w)| w * s + j as f64).collect())
+            .collect();
+
+        // up to sign, scaled by the norm of the weights
+        let component = first_component(&rows);
+        let mean = signal.iter().sum::<f64>() / signal.len() as f64;
+        let scale = weights.iter().map(|w| w * w).sum::<f64>().sqrt();
+        let sign = component[5].signum() * (signal[5] - mean).signum();
+        for (c, s) in component.iter().zip(&signal) {
+            assert!((c - sign * scale * (s - mean)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn tracker_waits_for_a_full_window_then_a_hop() {
+        let mut tracker = tracker(10.0, 1000);
+        let signal = sine(0.25, 1.0, 12.0);
+        let results: Vec<usize> = signal.iter().enumerate()
+            .filter_map(|(i, s)| tracker.push(&sample(i, &[*s as f32, -*s as f32])).map(|_| i))
+            .collect();
+        // 200 samples fill the window, then one estimate a second
+        assert_eq!(results, [199, 219, 239]);
+    }
+
+    #[test]
+    fn tracker_starts_over_when_the_clock_goes_backwards() {
+        let mut tracker = tracker(10.0, 1000);
+        let signal = sine(0.25, 1.0, 10.0);
+        let push_all = |tracker: &mut VitalsTracker, start: usize| -> Vec<usize> {
+            signal.iter().enumerate()
+                .filter_map(|(i, s)| tracker.push(&sample(start + i, &[*s as f32])).map(|_| start + i))
+                .collect()
+        };
+        assert_eq!(push_all(&mut tracker, 100_000), [100_199]);
+
+        // the sensor restarted, the resampler breaks and the handler clears the tracker
+        tracker.clear();
+        assert_eq!(push_all(&mut tracker, 0), [199]);
+    }
+
+    #[test]
+    fn tracker_starts_over_on_a_layout_change() {
+        let mut tracker = tracker(1.0, 0);
+        for i in 0..20 {
+            tracker.push(&sample(i, &[i as f32]));
+        }
+        assert!(tracker.push(&sample(20, &[1.0, 2.0])).is_none());
+        assert_eq!(tracker.samples.len(), 1);
+    }
+}
@@ -0,0 +1,169 @@
+use ndarray::{Array, Axis, Ix2};
+use ndarray_stats::CorrelationExt;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+use crate::csi::CSIReading;
+use crate::record::Record;
+use crate::resample::Sample;
+
+// statistics over the samples of one link still inside buffer.window_ms
+pub struct WindowMetrics {
+    pub frames: usize,
+    // grid points in the window with no reading, see GapPolicy::Missing
+    pub missing: usize,
+    pub span_us: u128,
+    // mean pcc over every pair of frames, lower means more movement
+    pub mean_pcc: f32,
+    // variance of each subcarrier's power over the window, averaged and at its largest
+    pub mean_subcarrier_variance: f32,
+    pub max_subcarrier_variance: f32,
+    // variance of each frame's mean linear amplitude over the window
+    pub amplitude_variance: f32,
+}
+
+impl WindowMetrics {
+    // missing samples, and those narrower or wider than the newest, e.g. from before
+    // a layout change, are skipped
+    pub fn compute(buffer: &AllocRingBuffer<Sample>) -> Option<Self> {
+        let newest = buffer.back()?;
+        let width = newest.csi.ncols();
+        let frames: Vec<&Sample> = buffer.iter()
+            .filter(|s| !s.missing && s.csi.ncols() == width)
+            .collect();
+        if frames.len() < 2 || width < 2 {
+            return None
+        }
+
+        let rows: Vec<_> = frames.iter().map(|s| s.csi.row(0)).collect();
+        let matrix: Array<f32, Ix2> = ndarray::stack(Axis(0), &rows).ok()?;
+
+        // rows are the variables, so this correlates every frame against every other
+        let corr = matrix.pearson_correlation().ok()?;
+        let n = frames.len();
+        // frames without any variation across subcarriers, e.g. after dc removal, have no pcc
+        let pairs: Vec<f32> = (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+            .map(|(i, j)| corr[[i, j]])
+            .filter(|pcc| pcc.is_finite())
+            .collect();
+        if pairs.is_empty() {
+            return None
+        }
+        let mean_pcc = pairs.iter().sum::<f32>() / pairs.len() as f32;
+
+        let subcarrier_variance = matrix.var_axis(Axis(0), 0.0);
+        let mean_subcarrier_variance = subcarrier_variance.mean().unwrap_or(0.0);
+        let max_subcarrier_variance = subcarrier_variance.iter().copied().fold(0.0, f32::max);
+
+        let amplitude = matrix.mapv(|db| 10_f32.powf(db / 20.0)).mean_axis(Axis(1))?;
+        let amplitude_variance = amplitude.var(0.0);
+
+        Some(Self {
+            frames: n,
+            missing: buffer.iter().filter(|s| s.missing).count(),
+            span_us: newest.timestamp_us - frames[0].timestamp_us,
+            mean_pcc,
+            mean_subcarrier_variance,
+            max_subcarrier_variance,
+            amplitude_variance,
+        })
+    }
+
+    // tagged like the newest reading of the window
+    pub fn to_record(&self, reading: &CSIReading, measurement: &str) -> Record {
+        let record = Record::new(measurement, reading.timestamp_us)
+            .add_field("frames", self.frames as i64)
+            .add_field("missing", self.missing as i64)
+            .add_field("span_us", self.span_us as i64)
+            .add_field("mean_pcc", self.mean_pcc)
+            .add_field("mean_subcarrier_variance", self.mean_subcarrier_variance)
+            .add_field("max_subcarrier_variance", self.max_subcarrier_variance)
+            .add_field("amplitude_variance", self.amplitude_variance);
+        reading.add_tags(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(samples: Vec<Sample>) -> AllocRingBuffer<Sample> {
+        let mut buffer = AllocRingBuffer::new(16);
+        for sample in samples {
+            buffer.enqueue(sample);
+        }
+        buffer
+    }
+
+    fn sample(timestamp_us: u128, row: &[f32]) -> Sample {
+        Sample { timestamp_us, csi: Array::from_shape_vec((1, row.len()), row.to_vec()).unwrap(), missing: false }
+    }
+
+    #[test]
+    fn needs_two_frames() {
+        assert!(WindowMetrics::compute(&buffer(vec![])).is_none());
+        assert!(WindowMetrics::compute(&buffer(vec![sample(0, &[0.0, 1.0, 2.0])])).is_none());
+    }
+
+    #[test]
+    fn shifted_frames_are_fully_correlated() {
+        let metrics = WindowMetrics::compute(&buffer(vec![
+            sample(1_000, &[0.0, 1.0, 2.0, 3.0]),
+            sample(21_000, &[2.0, 3.0, 4.0, 5.0]),
+        ])).unwrap();
+
+        assert_eq!(metrics.frames, 2);
+        assert_eq!(metrics.missing, 0);
+        assert_eq!(metrics.span_us, 20_000);
+        assert!((metrics.mean_pcc - 1.0).abs() < 1e-6);
+        // every subcarrier moves by 2 dB, a population variance of 1
+        assert!((metrics.mean_subcarrier_variance - 1.0).abs() < 1e-6);
+        assert!((metrics.max_subcarrier_variance - 1.0).abs() < 1e-6);
+        // mean linear amplitudes of 1.19837 and 1.50866
+        assert!((metrics.amplitude_variance - 0.0240697).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mirrored_frames_are_anti_correlated() {
+        let metrics = WindowMetrics::compute(&buffer(vec![
+            sample(0, &[0.0, 1.0, 2.0, 3.0]),
+            sample(1, &[3.0, 2.0, 1.0, 0.0]),
+            sample(2, &[0.0, 1.0, 2.0, 3.0]),
+        ])).unwrap();
+
+        // pairs of -1, 1 and -1
+        assert!((metrics.mean_pcc + 1.0 / 3.0).abs() < 1e-6);
+        assert!((metrics.max_subcarrier_variance - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn skips_missing_and_other_widths() {
+        let mut missing = sample(2, &[9.0, 0.0, 9.0, 0.0]);
+        missing.missing = true;
+        let metrics = WindowMetrics::compute(&buffer(vec![
+            sample(0, &[5.0, 0.0, 5.0]),
+            sample(1, &[0.0, 1.0, 2.0, 3.0]),
+            missing,
+            sample(3, &[0.0, 1.0, 2.0, 3.0]),
+        ])).unwrap();
+
+        assert_eq!(metrics.frames, 2);
+        assert_eq!(metrics.missing, 1);
+        assert_eq!(metrics.span_us, 2);
+        assert!((metrics.mean_pcc - 1.0).abs() < 1e-6);
+        assert_eq!(metrics.max_subcarrier_variance, 0.0);
+    }
+
+    #[test]
+    fn flat_frames_have_no_pcc() {
+        let flat = buffer(vec![sample(0, &[1.0, 1.0, 1.0]), sample(1, &[2.0, 2.0, 2.0])]);
+        assert!(WindowMetrics::compute(&flat).is_none());
+
+        // a flat frame only drops the pairs it's in
+        let metrics = WindowMetrics::compute(&buffer(vec![
+            sample(0, &[1.0, 1.0, 1.0]),
+            sample(1, &[0.0, 1.0, 2.0]),
+            sample(2, &[0.0, 1.0, 2.0]),
+        ])).unwrap();
+        assert!((metrics.mean_pcc - 1.0).abs() < 1e-6);
+    }
+}
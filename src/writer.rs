@@ -0,0 +1,141 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// the file format side of a writer thread, fed from a bounded queue by the handlers
+pub trait Writer: Send + 'static {
+    type Item: Send + 'static;
+    // used in log messages, e.g. "archive"
+    const NAME: &'static str;
+    // what an Item is, e.g. "frames"
+    const ITEMS: &'static str;
+
+    fn write(&mut self, item: Self::Item) -> io::Result<()>;
+    // after every write, and whenever the queue has been idle for the tick interval
+    fn tick(&mut self) -> io::Result<()>;
+    // once every handler has stopped
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+// the handler side, items are queued here and written by the writer thread
+pub struct Queue<T> {
+    tx: SyncSender<T>,
+    dropped: AtomicU64,
+    name: &'static str,
+    items: &'static str,
+}
+
+impl<T> Queue<T> {
+    // never blocks the handler, items are dropped while the writer is behind
+    pub fn send(&self, item: T) {
+        match self.tx.try_send(item) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // don't flood the output while the disk is behind
+                if dropped.is_power_of_two() {
+                    eprintln!("The {} queue is full, {} {} dropped so far.", self.name, dropped, self.items);
+                }
+            }
+            // the writer thread has stopped and already said why
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+fn run<W: Writer>(mut writer: W, rx: Receiver<W::Item>, tick_interval: Duration) {
+    loop {
+        let result = match rx.recv_timeout(tick_interval) {
+            Ok(item) => writer.write(item),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            // every handler has stopped
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // a failing disk shouldn't take the rest of the server down with it
+        if let Err(e) = result.and_then(|_| writer.tick()) {
+            eprintln!("Could not write to the {}, it has stopped: {}", W::NAME, e);
+            return
+        }
+    }
+
+    if let Err(e) = writer.finish() {
+        eprintln!("Could not finish the {}: {}", W::NAME, e);
+    }
+}
+
+// starts the writer thread, which finishes and exits once the returned Queue is dropped
+pub fn start<W: Writer>(writer: W, queue_size: usize, tick_interval: Duration) -> (Queue<W::Item>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::sync_channel(queue_size);
+    let handle = thread::spawn(move || run(writer, rx, tick_interval));
+    let queue = Queue { tx, dropped: AtomicU64::new(0), name: W::NAME, items: W::ITEMS };
+    (queue, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // keeps a log of the calls it gets, failing writes of 0
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    impl Writer for Log {
+        type Item = u32;
+        const NAME: &'static str = "test";
+        const ITEMS: &'static str = "numbers";
+
+        fn write(&mut self, item: u32) -> io::Result<()> {
+            if item == 0 {
+                return Err(io::Error::other("zero"))
+            }
+            self.0.lock().unwrap().push(format!("write {}", item));
+            Ok(())
+        }
+
+        fn tick(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push(String::from("tick"));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push(String::from("finish"));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_then_finishes_once_the_queue_is_dropped() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (queue, handle) = start(Log(log.clone()), 4, Duration::from_secs(60));
+        queue.send(1);
+        queue.send(2);
+        drop(queue);
+        handle.join().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["write 1", "tick", "write 2", "tick", "finish"]);
+    }
+
+    #[test]
+    fn a_failed_write_stops_the_writer() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (queue, handle) = start(Log(log.clone()), 4, Duration::from_secs(60));
+        queue.send(0);
+        handle.join().unwrap();
+        // sending to a stopped writer is fine
+        queue.send(1);
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn full_queues_drop_items() {
+        let (tx, _rx) = mpsc::sync_channel(1);
+        let queue = Queue { tx, dropped: AtomicU64::new(0), name: "test", items: "numbers" };
+        for i in 0..3 {
+            queue.send(i);
+        }
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+    }
+}